@@ -16,83 +16,784 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-//! CLI script run as a cron job to parse dumps and save them as data files
+//! CLI script run as a cron job to parse dumps and save them as data files.
+//! Downloading a dump with `--url` requires `curl` to be installed.
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bzip2::read::BzDecoder;
+use chrono::{NaiveDate, SecondsFormat, Utc};
 use flate2::read::GzDecoder;
-use shorturls::{DomainTemplate, IndexTemplate};
-use std::{collections::HashMap, fs, io, io::BufRead, path::PathBuf};
+use log::{debug, error, info, LevelFilter};
+use rayon::prelude::*;
+use shorturls::{data_dir, DomainTemplate, IndexTemplate, PATHS_SUFFIX, SCHEMA_VERSION};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs, io,
+    io::{BufRead, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
 use url::Url;
 
+/// Maximum number of unparseable lines to keep in the errors log
+const MAX_ERROR_SAMPLES: usize = 100;
+
+/// Directory dumps are read from, set by the `SHORTURLS_DUMP_DIR`
+/// environment variable or the Toolforge dumps mount by default
+fn dump_dir() -> PathBuf {
+    env::var_os("SHORTURLS_DUMP_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/public/dumps/public/other/shorturls"))
+}
+
 /// Get a sorted list of all the available dumps
 fn find_dumps() -> Result<Vec<PathBuf>> {
-    let mut files: Vec<PathBuf> = fs::read_dir("/public/dumps/public/other/shorturls")?
+    let mut files: Vec<PathBuf> = fs::read_dir(dump_dir())?
         .filter(|f| f.is_ok())
         .map(|f| f.unwrap().path())
-        .filter(|f| f.to_str().unwrap().ends_with(".gz"))
+        .filter(|f| {
+            let name = f.to_str().unwrap();
+            name.ends_with(".gz") || name.ends_with(".bz2")
+        })
         .collect();
     files.sort();
     Ok(files)
 }
 
-/// Parse a dump into a data file
-fn save_dump(path: PathBuf) -> Result<()> {
-    let data = format!(
-        "./data/{}.data",
-        path.file_name().unwrap().to_str().unwrap()
+/// Open a dump for reading, picking the decompressor based on
+/// the file extension
+fn open_dump(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = fs::File::open(path)?;
+    Ok(decompress(path.to_str().unwrap(), file))
+}
+
+/// Stream a dump over HTTP(S). This uses curl, so TLS support doesn't
+/// have to be built in.
+fn open_url(url: &Url) -> Result<Box<dyn BufRead>> {
+    let mut child = process::Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .arg(url.as_str())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("Unable to run curl: {}", err))?;
+    let stdout = child.stdout.take().unwrap();
+    Ok(decompress(url.path(), CurlReader { child, stdout }))
+}
+
+/// Wrap a compressed dump in the decompressor for its file name
+fn decompress<R: Read + 'static>(name: &str, reader: R) -> Box<dyn BufRead> {
+    if name.ends_with(".bz2") {
+        Box::new(io::BufReader::new(BzDecoder::new(reader)))
+    } else {
+        Box::new(io::BufReader::new(GzDecoder::new(reader)))
+    }
+}
+
+/// Output of curl, which fails instead of just ending if the download
+/// didn't finish
+struct CurlReader {
+    child: process::Child,
+    stdout: process::ChildStdout,
+}
+
+impl Drop for CurlReader {
+    /// Stop the download if the dump wasn't read to the end, and reap
+    /// the process either way
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+impl Read for CurlReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("curl failed: {}", status)));
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Where a dump is read from
+enum Dump {
+    File(PathBuf),
+    Url(Url),
+}
+
+impl Dump {
+    /// File name of the dump, e.g. `shorturls-20200101.gz`
+    fn name(&self) -> String {
+        match self {
+            Dump::File(path) => path.file_name().unwrap().to_str().unwrap().to_string(),
+            Dump::Url(url) => url_name(url).unwrap_or_default().to_string(),
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn BufRead>> {
+        match self {
+            Dump::File(path) => open_dump(path),
+            Dump::Url(url) => open_url(url),
+        }
+    }
+}
+
+/// Last path segment of a URL
+fn url_name(url: &Url) -> Option<&str> {
+    url.path_segments()?.next_back()
+}
+
+/// Parse the URL of a dump, which has to be named like the dumps
+/// on disk since data files are named after it
+fn parse_dump_url(url: &str) -> Result<Url> {
+    let parsed = Url::parse(url).map_err(|err| anyhow!("Invalid URL {}: {}", url, err))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(anyhow!("Only http and https URLs are supported: {}", url));
+    }
+    let valid_name = url_name(&parsed)
+        .and_then(|name| name.strip_prefix("shorturls-"))
+        .and_then(|name| name.split_once('.'))
+        .is_some_and(|(date, ext)| {
+            NaiveDate::parse_from_str(date, "%Y%m%d").is_ok() && (ext == "gz" || ext == "bz2")
+        });
+    if !valid_name {
+        return Err(anyhow!(
+            "URL doesn't point to a dump (shorturls-YYYYMMDD.gz or .bz2): {}",
+            url
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Shown for `--help`
+const USAGE: &str = "\
+Usage: extract-data [-v...] [--force] [--only YYYYMMDD | --url URL]
+
+Parse the short URL dumps into data files.
+
+Options:
+  -v, --verbose  Log more, repeat for even more
+  --force        Rebuild data files even if they already exist
+  --only DATE    Only process the dump from this date (YYYYMMDD)
+  --url URL      Download a single dump over HTTP(S) instead of reading
+                 the dump directory; requires curl to be installed
+  -h, --help     Show this message
+
+Environment:
+  SHORTURLS_DUMP_DIR  Directory to read dumps from
+  SHORTURLS_DATA_DIR  Directory to write data files to
+";
+
+/// Command-line options
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    /// Print the usage instead of doing anything
+    help: bool,
+    /// Number of times `-v` was passed
+    verbosity: usize,
+    /// Rebuild data files even if they already exist
+    force: bool,
+    /// Only process the dump from this date (YYYYMMDD)
+    only: Option<String>,
+    /// Download a single dump instead of reading the dump directory
+    url: Option<Url>,
+}
+
+impl Args {
+    /// Parse arguments, not including the program name
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--force" => parsed.force = true,
+                "-h" | "--help" => parsed.help = true,
+                "--only" => {
+                    let date = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--only requires a date (YYYYMMDD)"))?;
+                    NaiveDate::parse_from_str(&date, "%Y%m%d").map_err(|_| {
+                        anyhow!("Invalid date for --only: {} (expected YYYYMMDD)", date)
+                    })?;
+                    parsed.only = Some(date);
+                }
+                "--url" => {
+                    let url = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--url requires the URL of a dump"))?;
+                    parsed.url = Some(parse_dump_url(&url)?);
+                }
+                "--verbose" => parsed.verbosity += 1,
+                _ if arg.len() > 1
+                    && arg.starts_with('-')
+                    && arg[1..].chars().all(|c| c == 'v') =>
+                {
+                    parsed.verbosity += arg.len() - 1
+                }
+                _ => return Err(anyhow!("Unknown argument: {}", arg)),
+            }
+        }
+        if parsed.only.is_some() && parsed.url.is_some() {
+            return Err(anyhow!("--only and --url can't be used together"));
+        }
+        Ok(parsed)
+    }
+
+    /// Whether the dump at the given path should be processed
+    fn wants(&self, path: &Path) -> bool {
+        match &self.only {
+            Some(date) => path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&format!("shorturls-{}.", date))),
+            None => true,
+        }
+    }
+}
+
+/// What happened when processing a dump
+enum Outcome {
+    /// All the data files already existed
+    Existing,
+    Processed {
+        lines: usize,
+        skipped: i32,
+    },
+    /// The dump couldn't be read, e.g. because it's truncated
+    Failed(String),
+}
+
+/// Marker left behind when a dump couldn't be processed, so the
+/// next run retries it even if some data files exist
+fn partial_marker(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.data.partial", name))
+}
+
+/// Process a dump into data files in `dir`, logging and recording
+/// any failure instead of aborting the whole run
+fn try_save_dump(dump: Dump, force: bool, dir: &Path) -> Outcome {
+    let name = dump.name();
+    match save_dump(dump, force, dir) {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            error!("{}: unable to process dump: {}", name, err);
+            if let Err(err) = fs::write(partial_marker(dir, &name), format!("{}\n", err)) {
+                error!("{}: unable to write partial marker: {}", name, err);
+            }
+            Outcome::Failed(name)
+        }
+    }
+}
+
+/// Parse a dump into a data file, plus a parallel data file
+/// keyed by host and path. Existing data files are left alone
+/// unless `force` is set.
+fn save_dump(dump: Dump, force: bool, dir: &Path) -> Result<Outcome> {
+    let name = dump.name();
+    let data = dir.join(format!("{}.data", name));
+    let paths_data = dir.join(format!("{}{}", name, PATHS_SUFFIX));
+    let marker = partial_marker(dir, &name);
+    let retry = marker.exists();
+    let have_data = !force && !retry && data.exists();
+    let have_paths = !force && !retry && paths_data.exists();
+    if have_data && have_paths {
+        debug!("{}: data files already exist", name);
+        return Ok(Outcome::Existing);
+    }
+    let (counts, skipped) = count_domains(&name, dump.open()?)?;
+    info!(
+        "{}: read {} lines, skipped {} malformed lines, {} unparseable URLs and {} URLs without a host",
+        name, counts.lines, skipped.malformed, skipped.unparseable, skipped.nohost
     );
-    if std::path::Path::new(&data).exists() {
-        return Ok(());
+    if !skipped.error_samples.is_empty() {
+        write_errors(
+            &dir.join(format!("{}.errors.log", name)),
+            &skipped.error_samples,
+        )?;
     }
-    let gz = GzDecoder::new(fs::File::open(path)?);
-    let buffered = io::BufReader::new(gz);
-    let mut counts: HashMap<String, i32> = HashMap::new();
-    for rline in buffered.lines() {
+    let generated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    if !have_data {
+        let mut index = build_index(counts.domains);
+        index.generated_at = Some(generated_at.clone());
+        index.source_dump = Some(name.clone());
+        index.schemes = counts.schemes;
+        index.code_lengths = counts.code_lengths;
+        index.skipped_unparseable = skipped.unparseable;
+        index.skipped_nohost = skipped.nohost;
+        index.skipped_malformed = skipped.malformed;
+        write_data(&data, index)?;
+    }
+    if !have_paths {
+        let mut index = build_index(counts.paths);
+        index.generated_at = Some(generated_at);
+        index.source_dump = Some(name.clone());
+        // Most paths are only shortened once, drop those or the
+        // file would be nearly as big as the dump itself
+        index.stats.retain(|entry| entry.count > 1);
+        write_data(&paths_data, index)?;
+    }
+    if retry {
+        fs::remove_file(&marker)?;
+    }
+    Ok(Outcome::Processed {
+        lines: counts.lines,
+        skipped: skipped.malformed + skipped.unparseable + skipped.nohost,
+    })
+}
+
+/// Everything counted from the lines of a dump
+#[derive(Default)]
+struct Counts {
+    lines: usize,
+    /// Short URLs per host
+    domains: HashMap<String, i32>,
+    /// Short URLs per host and path
+    paths: HashMap<String, i32>,
+    /// Short URLs per protocol
+    schemes: BTreeMap<String, i32>,
+    /// Short codes per length, in characters
+    code_lengths: BTreeMap<usize, i32>,
+}
+
+/// Lines of a dump that couldn't be counted
+#[derive(Default)]
+struct SkipStats {
+    /// Lines that aren't `<code>|<url>`
+    malformed: i32,
+    /// Lines with a URL that couldn't be parsed
+    unparseable: i32,
+    /// Lines with a URL that has no host
+    nohost: i32,
+    /// Some of the malformed and unparseable lines, prefixed by the error
+    error_samples: Vec<String>,
+}
+
+/// Whether a short code looks like one UrlShortener would generate,
+/// which are never empty and don't contain whitespace
+fn is_valid_code(code: &str) -> bool {
+    !code.is_empty() && !code.contains(char::is_whitespace)
+}
+
+/// Normalize a host so the same site is always counted together: URLs
+/// with non-special schemes keep the host's case, and a trailing dot
+/// refers to the same host
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+/// Count the short URLs in a dump, where each line is `<code>|<url>`.
+/// `name` is only used for logging.
+fn count_domains(name: &str, reader: impl BufRead) -> Result<(Counts, SkipStats)> {
+    let mut counts = Counts::default();
+    let mut skipped = SkipStats::default();
+    for rline in reader.lines() {
         let line = rline?;
-        let sp: Vec<&str> = line.splitn(2, '|').collect();
-        let parsed = match Url::parse(sp[1]) {
+        counts.lines += 1;
+        let (code, url) = match line.split_once('|') {
+            Some((code, url)) if is_valid_code(code) => (code, url),
+            _ => {
+                debug!("{}: malformed line: {}", name, line);
+                skipped.malformed += 1;
+                if skipped.error_samples.len() < MAX_ERROR_SAMPLES {
+                    skipped
+                        .error_samples
+                        .push(format!("malformed line\t{}", line));
+                }
+                continue;
+            }
+        };
+        *counts.code_lengths.entry(code.chars().count()).or_insert(0) += 1;
+        let parsed = match Url::parse(url) {
             Ok(url) => url,
             // In theory this shouldn't be possible since UrlShortener
             // should validate URLs, but it happens. TODO: Report this
             // upstream...to me.
-            Err(_) => {
+            Err(err) => {
+                debug!("{}: unparseable URL ({}): {}", name, err, line);
+                skipped.unparseable += 1;
+                if skipped.error_samples.len() < MAX_ERROR_SAMPLES {
+                    skipped.error_samples.push(format!("{}\t{}", err, line));
+                }
                 continue;
             }
         };
-        let domain = match parsed.host_str() {
-            Some(domain) => domain.to_string(),
-            None => {
+        let domain = match parsed.host_str().map(normalize_host) {
+            Some(domain) if !domain.is_empty() => domain,
+            _ => {
+                debug!("{}: URL without a host: {}", name, line);
+                skipped.nohost += 1;
                 continue;
             }
         };
-        let counter = counts.entry(domain).or_insert(0);
-        *counter += 1;
+        *counts
+            .paths
+            .entry(format!("{}{}", domain, parsed.path()))
+            .or_insert(0) += 1;
+        *counts.domains.entry(domain).or_insert(0) += 1;
+        *counts
+            .schemes
+            .entry(parsed.scheme().to_string())
+            .or_insert(0) += 1;
     }
+    Ok((counts, skipped))
+}
+
+/// Turn a map of counts into a sorted index
+fn build_index(counts: HashMap<String, i32>) -> IndexTemplate {
     let mut entries: Vec<DomainTemplate> = counts
-        .iter()
+        .into_iter()
         .map(|(domain, count)| DomainTemplate {
-            domain: domain.to_string(),
-            count: *count,
+            domain,
+            count,
+            // Computed when the data file is read
+            percentage: 0.0,
+            rank: 0,
+            unicode: None,
         })
         .collect();
     let mut total: i32 = 0;
     for entry in &entries {
         total += entry.count;
     }
-    entries.sort_by(|a, b| b.count.cmp(&a.count));
-    let index = IndexTemplate {
+    // Break ties by name so the output is the same on every run
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.domain.cmp(&b.domain)));
+    IndexTemplate {
+        schema_version: SCHEMA_VERSION,
+        distinct_domains: entries.len(),
         stats: entries,
         total,
-    };
-    // Save to data file
-    println!("Writing to {}", data);
-    serde_json::to_writer(fs::File::create(&data)?, &index)?;
+        ..Default::default()
+    }
+}
+
+/// Save to data file. It's written to a temporary file first and then
+/// renamed into place, so the webserver never reads a partial file.
+fn write_data(data: &Path, mut index: IndexTemplate) -> Result<()> {
+    // Entries may have been dropped since the index was built, and
+    // only what's stored is counted
+    index.distinct_domains = index.stats.len();
+    info!("Writing to {}", data.display());
+    let tmp = tmp_path(data);
+    let written = write_json(&tmp, &index).and_then(|()| Ok(fs::rename(&tmp, data)?));
+    if written.is_err() {
+        // Don't leave the partial file behind
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Write JSON to a file, making sure it made it to disk
+fn write_json(path: &Path, index: &IndexTemplate) -> Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer(&mut file, index)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Temporary path to write a data file to, in the same directory so it
+/// can be renamed over it. It doesn't end in `.data`, so it's never
+/// picked up as a data file.
+fn tmp_path(data: &Path) -> PathBuf {
+    let mut name = data.file_name().unwrap().to_os_string();
+    name.push(".tmp");
+    data.with_file_name(name)
+}
+
+/// Save sample unparseable lines, one per line prefixed by the parse error
+fn write_errors(log: &Path, samples: &[String]) -> Result<()> {
+    info!("Writing to {}", log.display());
+    let mut file = io::BufWriter::new(fs::File::create(log)?);
+    for sample in samples {
+        writeln!(file, "{}", sample)?;
+    }
+    file.flush()?;
     Ok(())
 }
 
+/// Log warnings and errors by default, `-v` adds info and `-vv` adds
+/// debug output. `RUST_LOG` takes precedence over both.
+fn init_logging(verbosity: usize) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Debug,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+}
+
 fn main() -> Result<()> {
-    for dump in find_dumps()? {
-        save_dump(dump)?
+    let args = Args::parse(env::args().skip(1))?;
+    if args.help {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    init_logging(args.verbosity);
+    let dumps: Vec<Dump> = match &args.url {
+        Some(url) => vec![Dump::Url(url.clone())],
+        None => find_dumps()?
+            .into_iter()
+            .filter(|path| args.wants(path))
+            .map(Dump::File)
+            .collect(),
+    };
+    if let Some(date) = &args.only {
+        if dumps.is_empty() {
+            return Err(anyhow!("No dump found for {}", date));
+        }
+    }
+    let dir = data_dir();
+    let outcomes = dumps
+        .into_par_iter()
+        .map(|dump| try_save_dump(dump, args.force, &dir))
+        .collect::<Vec<Outcome>>();
+    let mut failed = vec![];
+    let mut processed = 0;
+    let mut existing = 0;
+    let mut total_lines = 0;
+    let mut total_skipped = 0;
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Existing => existing += 1,
+            Outcome::Processed { lines, skipped } => {
+                processed += 1;
+                total_lines += lines;
+                total_skipped += skipped;
+            }
+            Outcome::Failed(name) => failed.push(name),
+        }
+    }
+    info!(
+        "Processed {} dumps ({} lines, {} skipped), {} already existed, {} failed",
+        processed,
+        total_lines,
+        total_skipped,
+        existing,
+        failed.len()
+    );
+    if !failed.is_empty() {
+        failed.sort();
+        return Err(anyhow!(
+            "Failed to process {} dumps: {}",
+            failed.len(),
+            failed.join(", ")
+        ));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bzip2::write::BzEncoder;
+    use flate2::write::GzEncoder;
+
+    const DUMP: &str = "a|https://en.wikipedia.org/wiki/Foo\n\
+                        b|https://en.wikipedia.org/wiki/Bar\n\
+                        c|https://example.org/\n";
+
+    /// Count domains in a dump the same way save_dump does
+    fn domain_counts(path: &Path) -> HashMap<String, i32> {
+        count_domains("test", open_dump(path).unwrap())
+            .unwrap()
+            .0
+            .domains
+    }
+
+    fn args(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(Args::default(), args(&[]).unwrap());
+        assert_eq!(
+            Args {
+                verbosity: 3,
+                force: true,
+                only: Some("20200101".to_string()),
+                url: None,
+                ..Default::default()
+            },
+            args(&["-vv", "--force", "--only", "20200101", "--verbose"]).unwrap()
+        );
+        assert!(args(&["--help"]).unwrap().help);
+        assert!(args(&["-h"]).unwrap().help);
+        assert!(args(&["--only"]).is_err());
+        assert!(args(&["--only", "2020-01-01"]).is_err());
+        assert!(args(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn test_args_url() {
+        let url = "https://dumps.wikimedia.org/other/shorturls/shorturls-20200101.gz";
+        let parsed = args(&["--url", url]).unwrap();
+        assert_eq!(Some(Url::parse(url).unwrap()), parsed.url);
+        let dump = Dump::Url(parsed.url.unwrap());
+        assert_eq!("shorturls-20200101.gz", dump.name());
+        assert!(args(&["--url", "https://example.org/shorturls-20200101.bz2"]).is_ok());
+        for bad in [
+            "not a url",
+            "ftp://example.org/shorturls-20200101.gz",
+            "https://example.org/",
+            "https://example.org/shorturls-20200101.xz",
+            "https://example.org/shorturls-2020.gz",
+        ] {
+            assert!(args(&["--url", bad]).is_err(), "{}", bad);
+        }
+        assert!(args(&["--url"]).is_err());
+        assert!(args(&["--url", url, "--only", "20200101"]).is_err());
+    }
+
+    #[test]
+    fn test_args_wants() {
+        let only = args(&["--only", "20200101"]).unwrap();
+        assert!(only.wants(Path::new("/dumps/shorturls-20200101.gz")));
+        assert!(only.wants(Path::new("/dumps/shorturls-20200101.bz2")));
+        assert!(!only.wants(Path::new("/dumps/shorturls-20200108.gz")));
+        assert!(Args::default().wants(Path::new("/dumps/shorturls-20200108.gz")));
+    }
+
+    #[test]
+    fn test_count_domains() {
+        let dump = "a|https://en.wikipedia.org/wiki/Foo\n\
+                    b|https://en.wikipedia.org/wiki/Foo\n\
+                    c|http://en.wikipedia.org/wiki/Bar\n\
+                    d|https://example.org/\n\
+                    e|not a url\n\
+                    f|https://[::1\n\
+                    g|mailto:someone@example.org\n";
+        let (counts, skipped) = count_domains("test", dump.as_bytes()).unwrap();
+        assert_eq!(7, counts.lines);
+        assert_eq!(3, counts.domains["en.wikipedia.org"]);
+        assert_eq!(1, counts.domains["example.org"]);
+        assert_eq!(2, counts.domains.len());
+        assert_eq!(2, counts.paths["en.wikipedia.org/wiki/Foo"]);
+        assert_eq!(1, counts.paths["en.wikipedia.org/wiki/Bar"]);
+        assert_eq!(1, counts.paths["example.org/"]);
+        assert_eq!(3, counts.schemes["https"]);
+        assert_eq!(1, counts.schemes["http"]);
+        // Codes are counted even if their URL is skipped
+        assert_eq!(Some(&7), counts.code_lengths.get(&1));
+        assert_eq!(2, skipped.unparseable);
+        assert_eq!(1, skipped.nohost);
+        assert_eq!(2, skipped.error_samples.len());
+        assert!(skipped.error_samples[0].ends_with("\te|not a url"));
+    }
+
+    #[test]
+    fn test_count_domains_normalized() {
+        let dump = "a|https://en.wikipedia.org/wiki/Foo\n\
+                    b|https://EN.Wikipedia.ORG/wiki/Foo\n\
+                    c|https://en.wikipedia.org./wiki/Foo\n\
+                    d|foo://EN.WIKIPEDIA.ORG../\n\
+                    e|https://./\n";
+        let (counts, skipped) = count_domains("test", dump.as_bytes()).unwrap();
+        assert_eq!(4, counts.domains["en.wikipedia.org"]);
+        assert_eq!(1, counts.domains.len());
+        assert_eq!(3, counts.paths["en.wikipedia.org/wiki/Foo"]);
+        assert_eq!(1, skipped.nohost);
+    }
+
+    #[test]
+    fn test_count_domains_malformed() {
+        let dump = "https://en.wikipedia.org/\n\
+                    |https://en.wikipedia.org/\n\
+                    a b|https://en.wikipedia.org/\n\
+                    \n\
+                    a|https://en.wikipedia.org/wiki/A|B\n";
+        let (counts, skipped) = count_domains("test", dump.as_bytes()).unwrap();
+        assert_eq!(5, counts.lines);
+        assert_eq!(4, skipped.malformed);
+        assert_eq!(0, skipped.unparseable);
+        assert_eq!(4, skipped.error_samples.len());
+        assert_eq!(
+            vec![(1, 1)],
+            counts.code_lengths.into_iter().collect::<Vec<_>>()
+        );
+        // Only the first pipe separates the code from the URL
+        assert_eq!(1, counts.paths["en.wikipedia.org/wiki/A|B"]);
+    }
+
+    #[test]
+    fn test_count_domains_samples() {
+        let dump = "x|bogus\n".repeat(MAX_ERROR_SAMPLES + 5);
+        let (counts, skipped) = count_domains("test", dump.as_bytes()).unwrap();
+        assert!(counts.domains.is_empty());
+        assert_eq!(MAX_ERROR_SAMPLES as i32 + 5, skipped.unparseable);
+        assert_eq!(MAX_ERROR_SAMPLES, skipped.error_samples.len());
+    }
+
+    #[test]
+    fn test_write_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("shorturls-20200101.gz.data");
+        let mut domains = HashMap::new();
+        domains.insert("en.wikipedia.org".to_string(), 2);
+        write_data(&data, build_index(domains)).unwrap();
+        let index: IndexTemplate =
+            serde_json::from_str(&fs::read_to_string(&data).unwrap()).unwrap();
+        assert_eq!(2, index.total);
+        assert_eq!(1, index.distinct_domains);
+        assert_eq!(
+            dir.path().join("shorturls-20200101.gz.data.tmp"),
+            tmp_path(&data)
+        );
+        assert!(!tmp_path(&data).exists());
+        assert_eq!(vec![data], shorturls::find_data_in(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gz = GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(DUMP.as_bytes()).unwrap();
+        let mut bytes = gz.finish().unwrap();
+        bytes.truncate(bytes.len() / 2);
+        let path = dir.path().join("shorturls-20200101.gz");
+        fs::write(&path, bytes).unwrap();
+        let lines: io::Result<Vec<String>> = open_dump(&path).unwrap().lines().collect();
+        assert!(lines.is_err());
+        // Only the marker is written, so the next run retries the dump
+        let data_dir = dir.path().join("data");
+        fs::create_dir(&data_dir).unwrap();
+        assert!(matches!(
+            try_save_dump(Dump::File(path), false, &data_dir),
+            Outcome::Failed(_)
+        ));
+        assert!(partial_marker(&data_dir, "shorturls-20200101.gz").exists());
+        assert!(!data_dir.join("shorturls-20200101.gz.data").exists());
+        assert!(shorturls::find_data_in(&data_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let gz_path = dir.path().join("shorturls-20200101.gz");
+        let mut gz = GzEncoder::new(
+            fs::File::create(&gz_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(DUMP.as_bytes()).unwrap();
+        gz.finish().unwrap();
+        let bz2_path = dir.path().join("shorturls-20200101.bz2");
+        let mut bz2 = BzEncoder::new(
+            fs::File::create(&bz2_path).unwrap(),
+            bzip2::Compression::default(),
+        );
+        bz2.write_all(DUMP.as_bytes()).unwrap();
+        bz2.finish().unwrap();
+
+        let counts = domain_counts(&gz_path);
+        assert_eq!(counts["en.wikipedia.org"], 2);
+        assert_eq!(counts["example.org"], 1);
+        assert_eq!(counts, domain_counts(&bz2_path));
+    }
+}
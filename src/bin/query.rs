@@ -0,0 +1,49 @@
+/*
+Statistics about w.wiki
+Copyright (C) 2020 Kunal Mehta <legoktm@debian.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! CLI script to look up a domain in the latest data file, without
+//! needing the webserver or Redis
+
+use anyhow::{anyhow, Result};
+use shorturls::{find_data, load_data};
+use std::env;
+
+fn main() -> Result<()> {
+    let domain = match env::args().nth(1) {
+        Some(domain) => domain,
+        None => return Err(anyhow!("Usage: query <domain>")),
+    };
+    let latest = find_data()?
+        .pop()
+        .ok_or_else(|| anyhow!("No data available yet"))?;
+    let data = load_data(&latest)?;
+    let dinfo = data
+        .stats
+        .iter()
+        .find(|dinfo| dinfo.domain == domain)
+        .ok_or_else(|| anyhow!("{} isn't in the latest data", domain))?;
+    println!(
+        "{}: {} short URLs ({}%), ranked #{} of {}",
+        dinfo.domain,
+        dinfo.count,
+        dinfo.percentage,
+        dinfo.rank,
+        data.stats.len()
+    );
+    Ok(())
+}
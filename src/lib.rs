@@ -25,31 +25,638 @@ along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //! and corresponding API endpoints.
 
 use anyhow::Result;
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+/// Version of the data file format written by `extract_data`. Bump this
+/// whenever the format changes and add a step to `IndexTemplate::migrate`.
+pub const SCHEMA_VERSION: u32 = 1;
 
 /// Tera template for the index, but also the structure of data files
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct IndexTemplate {
+    /// Data files from before versioning was added are version 0
+    #[serde(default)]
+    pub schema_version: u32,
     pub stats: Vec<DomainTemplate>,
     pub total: i32,
+    /// Number of entries stored in the data file, which may be more than
+    /// are in `stats` once it's been filtered for display. Paths data files
+    /// leave out paths that were only shortened once, so they don't count
+    /// every path in the dump.
+    #[serde(default)]
+    pub distinct_domains: usize,
+    /// Number of short URLs per protocol (e.g. `https`)
+    #[serde(default)]
+    pub schemes: BTreeMap<String, i32>,
+    /// Number of lines in the dump with a URL that couldn't be parsed
+    #[serde(default)]
+    pub skipped_unparseable: i32,
+    /// Number of lines in the dump with a URL that has no host
+    #[serde(default)]
+    pub skipped_nohost: i32,
+    /// Number of lines in the dump that aren't `<code>|<url>`
+    #[serde(default)]
+    pub skipped_malformed: i32,
+    /// Number of short codes of each length
+    #[serde(default)]
+    pub code_lengths: BTreeMap<usize, i32>,
+    /// When the data file was written (RFC 3339, UTC), missing from
+    /// older data files
+    #[serde(default)]
+    pub generated_at: Option<String>,
+    /// File name of the dump the data file was extracted from
+    #[serde(default)]
+    pub source_dump: Option<String>,
+}
+
+impl IndexTemplate {
+    /// Upgrade data read from an older data file to the current format.
+    /// Returns whether anything was changed; data from a newer version is
+    /// left alone.
+    pub fn migrate(&mut self) -> bool {
+        if self.schema_version >= SCHEMA_VERSION {
+            return false;
+        }
+        if self.schema_version < 1 {
+            // Ties used to be left in whatever order the HashMap had them
+            self.stats
+                .sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.domain.cmp(&b.domain)));
+        }
+        self.schema_version = SCHEMA_VERSION;
+        true
+    }
+
+    /// Fill in each domain's share of the total, which isn't
+    /// stored in the data files
+    pub fn compute_percentages(&mut self) {
+        for dinfo in &mut self.stats {
+            dinfo.percentage = if self.total > 0 {
+                // Round to two decimal places
+                (dinfo.count as f32 / self.total as f32 * 10000.0).round() / 100.0
+            } else {
+                0.0
+            };
+        }
+    }
+
+    /// Merge domains that share the same key into a single entry,
+    /// keeping the result sorted by count
+    pub fn grouped<F: Fn(&str) -> String>(self, key: F) -> IndexTemplate {
+        let mut counts: HashMap<String, i32> = HashMap::new();
+        for dinfo in self.stats {
+            *counts.entry(key(&dinfo.domain)).or_insert(0) += dinfo.count;
+        }
+        let mut stats: Vec<DomainTemplate> = counts
+            .into_iter()
+            .map(|(domain, count)| DomainTemplate {
+                domain,
+                count,
+                percentage: 0.0,
+                rank: 0,
+                unicode: None,
+            })
+            .collect();
+        stats.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.domain.cmp(&b.domain)));
+        let mut index = IndexTemplate {
+            distinct_domains: stats.len(),
+            stats,
+            ..self
+        };
+        index.compute_percentages();
+        index.compute_ranks();
+        index.decode_hosts();
+        index
+    }
+
+    /// Count the short URLs that point to Wikimedia sites
+    pub fn count_wikimedia(&self) -> i32 {
+        self.stats
+            .iter()
+            .filter(|dinfo| is_wikimedia(&dinfo.domain))
+            .map(|dinfo| dinfo.count)
+            .sum()
+    }
+
+    /// Fill in each domain's rank, which is just its (1-based)
+    /// position since `stats` is sorted by count
+    pub fn compute_ranks(&mut self) {
+        for (idx, dinfo) in self.stats.iter_mut().enumerate() {
+            dinfo.rank = idx + 1;
+        }
+    }
+
+    /// Fill in the Unicode form of internationalized domains
+    pub fn decode_hosts(&mut self) {
+        for dinfo in self.stats.iter_mut() {
+            dinfo.unicode = unicode_host(&dinfo.domain);
+        }
+    }
+}
+
+/// Decode a punycode (`xn--`) host into Unicode. Returns `None` if the
+/// host isn't internationalized or can't be decoded, in which case the
+/// raw host should be shown.
+pub fn unicode_host(host: &str) -> Option<String> {
+    if !host.split('.').any(|label| label.starts_with("xn--")) {
+        return None;
+    }
+    match idna::domain_to_unicode(host) {
+        (unicode, Ok(())) if unicode != host => Some(unicode),
+        _ => None,
+    }
 }
 
 /// Tera template for domain pages
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct DomainTemplate {
     pub domain: String,
     pub count: i32,
+    /// Percentage of all short URLs that point to this domain
+    #[serde(default)]
+    pub percentage: f32,
+    /// Position of this domain when sorted by count
+    #[serde(default)]
+    pub rank: usize,
+    /// Unicode form of an internationalized domain, which is stored in
+    /// its punycode form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unicode: Option<String>,
+}
+
+/// Dated counts from a run of data files, as drawn on the charts
+#[derive(Default)]
+pub struct ChartSeries {
+    /// Total number of short URLs in each data file
+    pub total: Vec<(NaiveDate, i32)>,
+    /// Count for a single domain, only in the data files it appears in
+    pub domain: Vec<(NaiveDate, i32)>,
+    /// Number of distinct domains in each data file
+    pub domains: Vec<(NaiveDate, usize)>,
+}
+
+impl ChartSeries {
+    /// Add the counts from a data file, which should be newer than
+    /// the ones that were already added
+    pub fn push(&mut self, date: NaiveDate, data: &IndexTemplate, domain: Option<&str>) {
+        self.total.push((date, data.total));
+        self.domains.push((date, data.stats.len()));
+        if let Some(host) = domain {
+            if let Some(dinfo) = data.stats.iter().find(|dinfo| dinfo.domain == host) {
+                self.domain.push((date, dinfo.count));
+            }
+        }
+    }
+
+    /// Add all the points from another series, which should
+    /// only contain newer data files
+    pub fn append(&mut self, mut other: ChartSeries) {
+        self.total.append(&mut other.total);
+        self.domain.append(&mut other.domain);
+        self.domains.append(&mut other.domains);
+    }
+
+    /// Dates of all the data files that were added
+    pub fn dates(&self) -> Vec<NaiveDate> {
+        self.total.iter().map(|(date, _)| *date).collect()
+    }
+
+    /// Mean number of short URLs per distinct domain in each data file,
+    /// skipping any without domains
+    pub fn average_per_domain(&self) -> Vec<(NaiveDate, f32)> {
+        self.total
+            .iter()
+            .zip(&self.domains)
+            .filter(|(_, (_, domains))| *domains > 0)
+            .map(|((date, total), (_, domains))| (*date, *total as f32 / *domains as f32))
+            .collect()
+    }
+}
+
+/// Get the registrable domain (eTLD+1) of a host, e.g. `wikipedia.org` for
+/// `en.wikipedia.org`. Returns `None` for IP addresses and hosts that don't
+/// have one (e.g. a bare public suffix).
+pub fn registrable_domain(host: &str) -> Option<&str> {
+    if is_ip(host) {
+        return None;
+    }
+    psl::domain_str(host)
+}
+
+/// Get the public suffix (eTLD) of a host, e.g. `org` for `en.wikipedia.org`
+/// or `co.uk` for `www.example.co.uk`. Returns `None` for IP addresses and
+/// hosts without a dot.
+pub fn public_suffix(host: &str) -> Option<&str> {
+    if is_ip(host) || !host.contains('.') {
+        return None;
+    }
+    psl::suffix_str(host)
+}
+
+/// Registrable domains (eTLD+1) that are run by Wikimedia, besides the
+/// `CLOUD_VPS_DOMAINS`
+pub const WIKIMEDIA_DOMAINS: &[&str] = &[
+    "mediawiki.org",
+    "w.wiki",
+    "wikibooks.org",
+    "wikidata.org",
+    "wikifunctions.org",
+    "wikimedia.org",
+    "wikimediafoundation.org",
+    "wikinews.org",
+    "wikipedia.org",
+    "wikiquote.org",
+    "wikisource.org",
+    "wikiversity.org",
+    "wikivoyage.org",
+    "wiktionary.org",
+];
+
+/// Domains that Toolforge tools and Cloud VPS projects are hosted under
+pub const CLOUD_VPS_DOMAINS: &[&str] = &["toolforge.org", "wmcloud.org", "wmflabs.org"];
+
+/// Whether a host belongs to one of the `CLOUD_VPS_DOMAINS`
+fn is_cloud_vps(host: &str) -> bool {
+    // Some of the Cloud VPS domains are public suffixes themselves
+    [registrable_domain(host), public_suffix(host)]
+        .iter()
+        .flatten()
+        .any(|domain| CLOUD_VPS_DOMAINS.contains(domain))
+}
+
+/// Whether a host belongs to one of the `WIKIMEDIA_DOMAINS` or
+/// `CLOUD_VPS_DOMAINS`
+pub fn is_wikimedia(host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    registrable_domain(&host).is_some_and(|domain| WIKIMEDIA_DOMAINS.contains(&domain))
+        || is_cloud_vps(&host)
+}
+
+/// Classify a host into a Wikimedia project family, e.g. `wikipedia` for
+/// `en.m.wikipedia.org` or `commons` for `commons.wikimedia.org`. Hosts
+/// that don't belong to a Wikimedia project are `external`.
+pub fn project_family(host: &str) -> &'static str {
+    let host = host.to_ascii_lowercase();
+    let subdomain = host.split('.').next().unwrap_or_default();
+    if is_cloud_vps(&host) {
+        return "cloud";
+    }
+    match registrable_domain(&host) {
+        Some("wikipedia.org") => "wikipedia",
+        Some("wiktionary.org") => "wiktionary",
+        Some("wikibooks.org") => "wikibooks",
+        Some("wikinews.org") => "wikinews",
+        Some("wikiquote.org") => "wikiquote",
+        Some("wikisource.org") => "wikisource",
+        Some("wikiversity.org") => "wikiversity",
+        Some("wikivoyage.org") => "wikivoyage",
+        Some("wikidata.org") => "wikidata",
+        Some("wikifunctions.org") => "wikifunctions",
+        Some("mediawiki.org") => "mediawiki",
+        Some("wikimedia.org") => match subdomain {
+            "commons" => "commons",
+            "meta" => "meta",
+            "species" => "wikispecies",
+            _ => "wikimedia",
+        },
+        Some("wikimediafoundation.org") | Some("w.wiki") => "wikimedia",
+        _ => "external",
+    }
 }
 
-/// Get a sorted list of all the data files
+/// Whether the host is an IPv4 or (bracketed) IPv6 address
+fn is_ip(host: &str) -> bool {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok()
+}
+
+/// Suffix of data files that are keyed by host and path
+/// instead of just host
+pub const PATHS_SUFFIX: &str = ".paths.data";
+
+/// Directory data files are stored in, set by the `SHORTURLS_DATA_DIR`
+/// environment variable or `./data` by default
+pub fn data_dir() -> PathBuf {
+    env::var_os("SHORTURLS_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./data"))
+}
+
+/// Read a data file and fill in the fields that aren't stored in it,
+/// for tools that don't go through the webserver's Redis cache
+pub fn load_data(path: &Path) -> Result<IndexTemplate> {
+    let mut data: IndexTemplate = serde_json::from_str(&fs::read_to_string(path)?)?;
+    data.migrate();
+    data.compute_percentages();
+    data.compute_ranks();
+    data.decode_hosts();
+    data.distinct_domains = data.stats.len();
+    Ok(data)
+}
+
+/// Get a sorted list of all the (domain) data files
 pub fn find_data() -> Result<Vec<PathBuf>> {
-    let mut files: Vec<PathBuf> = fs::read_dir("./data")?
-        // TODO: use filter_map
-        .filter(|f| f.is_ok())
-        .map(|f| f.unwrap().path())
-        .filter(|f| f.to_str().unwrap().ends_with(".data"))
+    find_data_in(&data_dir())
+}
+
+/// Get a sorted list of all the (domain) data files in the given directory.
+/// Unreadable entries and non-UTF-8 filenames are skipped, and a missing
+/// directory (e.g. before the first extraction) is treated as empty. If a
+/// date was extracted from both a gzip and a bzip2 dump, only the gzip
+/// one is listed.
+pub fn find_data_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if !path.is_file() {
+                return None;
+            }
+            let name = path.to_str()?;
+            if name.ends_with(".data") && !name.ends_with(PATHS_SUFFIX) {
+                Some(path)
+            } else {
+                None
+            }
+        })
         .collect();
-    files.sort();
+    files.sort_by_cached_key(|path| (data_stem(path).to_string(), !is_gzip_data(path)));
+    files.dedup_by(|later, earlier| data_stem(later) == data_stem(earlier));
     Ok(files)
 }
+
+/// Data file name without its extensions, e.g. `shorturls-20200101`
+fn data_stem(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .unwrap_or_default()
+}
+
+/// Whether a data file was extracted from a gzip dump
+fn is_gzip_data(path: &Path) -> bool {
+    path.to_str().is_some_and(|name| name.ends_with(".gz.data"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compute() {
+        let mut index = IndexTemplate {
+            schema_version: SCHEMA_VERSION,
+            stats: vec![
+                DomainTemplate {
+                    domain: "en.wikipedia.org".to_string(),
+                    count: 2,
+                    percentage: 0.0,
+                    rank: 0,
+                    unicode: None,
+                },
+                DomainTemplate {
+                    domain: "query.wikidata.org".to_string(),
+                    count: 1,
+                    percentage: 0.0,
+                    rank: 0,
+                    unicode: None,
+                },
+            ],
+            total: 3,
+            distinct_domains: 2,
+            ..Default::default()
+        };
+        index.compute_percentages();
+        assert_eq!(66.67, index.stats[0].percentage);
+        assert_eq!(33.33, index.stats[1].percentage);
+        index.compute_ranks();
+        assert_eq!(1, index.stats[0].rank);
+        assert_eq!(2, index.stats[1].rank);
+    }
+
+    #[test]
+    fn test_grouped() {
+        let stats = [
+            ("en.wikipedia.org", 3),
+            ("de.wikipedia.org", 2),
+            ("wikidata.org", 4),
+        ]
+        .iter()
+        .map(|(domain, count)| DomainTemplate {
+            domain: domain.to_string(),
+            count: *count,
+            percentage: 0.0,
+            rank: 0,
+            unicode: None,
+        })
+        .collect();
+        let index = IndexTemplate {
+            schema_version: SCHEMA_VERSION,
+            stats,
+            total: 9,
+            distinct_domains: 3,
+            ..Default::default()
+        }
+        .grouped(|host| registrable_domain(host).unwrap_or(host).to_string());
+        let summary: Vec<(&str, i32, usize)> = index
+            .stats
+            .iter()
+            .map(|dinfo| (dinfo.domain.as_str(), dinfo.count, dinfo.rank))
+            .collect();
+        assert_eq!(
+            vec![("wikipedia.org", 5, 1), ("wikidata.org", 4, 2)],
+            summary
+        );
+        assert_eq!(9, index.total);
+        assert_eq!(2, index.distinct_domains);
+    }
+
+    #[test]
+    fn test_migrate() {
+        // Version 0 files don't have a schema_version, and ties may be
+        // in any order
+        let mut index: IndexTemplate = serde_json::from_str(
+            r#"{"stats":[{"domain":"b.org","count":2},{"domain":"c.org","count":1},{"domain":"a.org","count":2}],"total":5}"#,
+        )
+        .unwrap();
+        assert_eq!(0, index.schema_version);
+        assert_eq!(None, index.generated_at);
+        assert_eq!(None, index.source_dump);
+        assert!(index.migrate());
+        assert_eq!(SCHEMA_VERSION, index.schema_version);
+        let domains: Vec<&str> = index.stats.iter().map(|d| d.domain.as_str()).collect();
+        assert_eq!(vec!["a.org", "b.org", "c.org"], domains);
+        // Already migrated
+        assert!(!index.migrate());
+        // Newer versions are left alone
+        index.schema_version = SCHEMA_VERSION + 1;
+        assert!(!index.migrate());
+        assert_eq!(SCHEMA_VERSION + 1, index.schema_version);
+    }
+
+    #[test]
+    fn test_registrable_domain() {
+        assert_eq!(
+            Some("wikipedia.org"),
+            registrable_domain("en.wikipedia.org")
+        );
+        assert_eq!(
+            Some("wikipedia.org"),
+            registrable_domain("en.m.wikipedia.org")
+        );
+        assert_eq!(
+            Some("example.co.uk"),
+            registrable_domain("www.example.co.uk")
+        );
+        assert_eq!(Some("wikidata.org"), registrable_domain("wikidata.org"));
+        assert_eq!(None, registrable_domain("127.0.0.1"));
+        assert_eq!(None, registrable_domain("[::1]"));
+    }
+
+    #[test]
+    fn test_public_suffix() {
+        assert_eq!(Some("org"), public_suffix("en.wikipedia.org"));
+        assert_eq!(Some("co.uk"), public_suffix("www.example.co.uk"));
+        assert_eq!(None, public_suffix("localhost"));
+        assert_eq!(None, public_suffix("10.0.0.1"));
+        assert_eq!(None, public_suffix("[2001:db8::1]"));
+    }
+
+    #[test]
+    fn test_chart_series() {
+        let index = |stats: &[(&str, i32)]| IndexTemplate {
+            schema_version: SCHEMA_VERSION,
+            stats: stats
+                .iter()
+                .map(|(domain, count)| DomainTemplate {
+                    domain: domain.to_string(),
+                    count: *count,
+                    percentage: 0.0,
+                    rank: 0,
+                    unicode: None,
+                })
+                .collect(),
+            total: stats.iter().map(|(_, count)| count).sum(),
+            distinct_domains: stats.len(),
+            ..Default::default()
+        };
+        let first = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        let second = NaiveDate::from_ymd_opt(2020, 1, 8).unwrap();
+        let mut series = ChartSeries::default();
+        series.push(first, &index(&[("example.org", 1)]), Some("wikidata.org"));
+        series.push(
+            second,
+            &index(&[("wikidata.org", 2), ("example.org", 1)]),
+            Some("wikidata.org"),
+        );
+        assert_eq!(vec![(first, 1), (second, 3)], series.total);
+        assert_eq!(vec![(second, 2)], series.domain);
+        assert_eq!(vec![(first, 1), (second, 2)], series.domains);
+        assert_eq!(vec![first, second], series.dates());
+        assert_eq!(
+            vec![(first, 1.0), (second, 1.5)],
+            series.average_per_domain()
+        );
+        series.push(
+            NaiveDate::from_ymd_opt(2020, 1, 15).unwrap(),
+            &index(&[]),
+            None,
+        );
+        assert_eq!(2, series.average_per_domain().len());
+
+        let third = NaiveDate::from_ymd_opt(2020, 1, 22).unwrap();
+        let mut newer = ChartSeries::default();
+        newer.push(third, &index(&[("wikidata.org", 4)]), Some("wikidata.org"));
+        series.append(newer);
+        assert_eq!(vec![(second, 2), (third, 4)], series.domain);
+        assert_eq!(Some(&third), series.dates().last());
+    }
+
+    #[test]
+    fn test_unicode_host() {
+        assert_eq!(
+            Some("яндекс.рф".to_string()),
+            unicode_host("xn--d1acpjx3f.xn--p1ai")
+        );
+        assert_eq!(
+            Some("bücher.example".to_string()),
+            unicode_host("xn--bcher-kva.example")
+        );
+        assert_eq!(None, unicode_host("en.wikipedia.org"));
+        // Not valid punycode, so the raw host is used
+        assert_eq!(None, unicode_host("xn--.example"));
+    }
+
+    #[test]
+    fn test_is_wikimedia() {
+        assert!(is_wikimedia("en.wikipedia.org"));
+        assert!(is_wikimedia("Commons.Wikimedia.org"));
+        assert!(is_wikimedia("w.wiki"));
+        assert!(is_wikimedia("shorturls.toolforge.org"));
+        assert!(!is_wikimedia("example.org"));
+        assert!(!is_wikimedia("wikipedia.org.example.com"));
+        assert!(!is_wikimedia("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_project_family() {
+        assert_eq!("wikipedia", project_family("en.wikipedia.org"));
+        assert_eq!("wikipedia", project_family("de.m.wikipedia.org"));
+        assert_eq!("wiktionary", project_family("fr.wiktionary.org"));
+        assert_eq!("wikivoyage", project_family("en.wikivoyage.org"));
+        assert_eq!("wikidata", project_family("www.wikidata.org"));
+        assert_eq!("wikidata", project_family("query.wikidata.org"));
+        assert_eq!("commons", project_family("commons.wikimedia.org"));
+        assert_eq!("commons", project_family("Commons.m.Wikimedia.org"));
+        assert_eq!("meta", project_family("meta.wikimedia.org"));
+        assert_eq!("wikimedia", project_family("phabricator.wikimedia.org"));
+        assert_eq!("mediawiki", project_family("www.mediawiki.org"));
+        assert_eq!("cloud", project_family("shorturls.toolforge.org"));
+        assert_eq!("external", project_family("example.org"));
+        assert_eq!("external", project_family("wikipedia.example.com"));
+        assert_eq!("external", project_family("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_find_data_in() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "shorturls-20200108.gz.data",
+            "shorturls-20200108.bz2.data",
+            "shorturls-20200101.gz.data",
+            "shorturls-20200101.gz.paths.data",
+            "shorturls-20200115.bz2.data",
+            "shorturls-20200101.gz",
+            "README",
+        ] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        fs::create_dir(dir.path().join("subdir.data")).unwrap();
+        assert_eq!(
+            vec![
+                dir.path().join("shorturls-20200101.gz.data"),
+                dir.path().join("shorturls-20200108.gz.data"),
+                dir.path().join("shorturls-20200115.bz2.data"),
+            ],
+            find_data_in(dir.path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_find_data_in_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(find_data_in(&dir.path().join("data")).unwrap().is_empty());
+    }
+}
@@ -15,283 +15,6383 @@ GNU Affero General Public License for more details.
 You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
+// The OpenAPI spec is one big json! invocation
+#![recursion_limit = "256"]
 
 use anyhow::{anyhow, Result};
-use chrono::NaiveDate;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use log::{error, info, warn, LevelFilter};
+use plotters::{
+    coord::Shift,
+    prelude::{DrawingArea, DrawingBackend},
+    style::{RGBColor, BLACK, BLUE, GREEN, RED, WHITE},
+};
 use redis::AsyncCommands;
-use rocket::http::ContentType;
-use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::config::LogLevel;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::figment::Figment;
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::fs::FileServer;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, stream::TextStream, Responder, Response};
+use rocket::serde::{
+    json::{json, Json},
+    Deserialize, Serialize,
+};
+use rocket::State;
 use rocket_dyn_templates::{
     tera::{Result as TeraResult, Value},
     Template,
 };
-use shorturls::{find_data, DomainTemplate, IndexTemplate};
-use std::{collections::HashMap, path::PathBuf};
-use thousands::Separable;
+use shorturls::{
+    find_data, project_family, public_suffix, registrable_domain, unicode_host, ChartSeries,
+    DomainTemplate, IndexTemplate, SCHEMA_VERSION,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, Once, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+use thousands::{Separable, SeparatorPolicy};
 use tokio::fs;
 
 #[macro_use]
 extern crate rocket;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 struct ErrorTemplate {
     error: String,
 }
 
-/// Connect to `tools-redis`
-fn connect_redis() -> Result<redis::Client> {
-    let host = if std::path::Path::new("/etc/wmcs-project").exists() {
-        "tools-redis"
-    } else {
-        "127.0.0.1"
-    };
-    Ok(redis::Client::open(format!("redis://{}:6379/", host))?)
+/// Error message used when a domain isn't present in the data
+const UNKNOWN_DOMAIN: &str = "Unknown domain specified";
+
+/// Error message used when there are no data files yet
+const NO_DATA: &str = "No data available yet";
+
+/// Errors that can happen while handling a request, each of which maps
+/// to an HTTP status
+#[derive(Debug)]
+enum AppError {
+    /// The domain (or date, etc.) isn't in the data
+    NotFound(String),
+    /// Invalid path or query parameters
+    BadRequest(String),
+    /// There are no data files yet
+    NoData,
+    /// Talking to Redis failed
+    RedisUnavailable(redis::RedisError),
+    /// A data file exists but can't be deserialized
+    DataCorrupt {
+        /// Date of the data file, or its name if it doesn't have one
+        date: String,
+        source: serde_json::Error,
+    },
+    Io(std::io::Error),
+    /// Anything else
+    Other(anyhow::Error),
 }
 
-#[get("/")]
-async fn index() -> Template {
-    match build_index().await {
-        Ok(index) => Template::render("main", index),
-        Err(err) => {
-            dbg!(&err);
-            Template::render(
-                "error",
-                ErrorTemplate {
-                    error: err.to_string(),
-                },
-            )
+impl AppError {
+    /// Error for a domain that isn't present in the data
+    fn unknown_domain() -> Self {
+        Self::NotFound(UNKNOWN_DOMAIN.to_string())
+    }
+
+    /// Error for a data file that can't be deserialized, describing the
+    /// file by its date
+    fn corrupt(path: &Path, source: serde_json::Error) -> Self {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let date = match parse_date(name) {
+            Ok(date) => date.format("%Y-%m-%d").to_string(),
+            Err(_) => name.to_string(),
+        };
+        Self::DataCorrupt { date, source }
+    }
+
+    fn status(&self) -> Status {
+        match self {
+            Self::NotFound(_) => Status::NotFound,
+            Self::BadRequest(_) => Status::BadRequest,
+            Self::NoData | Self::RedisUnavailable(_) => Status::ServiceUnavailable,
+            Self::DataCorrupt { .. } | Self::Io(_) | Self::Other(_) => Status::InternalServerError,
+        }
+    }
+
+    /// Body shared by the JSON and HTML representations
+    fn template(&self) -> ErrorTemplate {
+        ErrorTemplate {
+            error: self.to_string(),
         }
     }
 }
 
-#[get("/<domain>")]
-async fn domain(domain: String) -> Template {
-    match build_domain(domain).await {
-        Ok(dinfo) => Template::render("domain", dinfo),
-        Err(error) => Template::render("error", error),
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(error) | Self::BadRequest(error) => write!(f, "{}", error),
+            Self::NoData => write!(f, "{}", NO_DATA),
+            Self::RedisUnavailable(err) => write!(f, "redis error: {}", err),
+            Self::DataCorrupt { date, .. } => write!(f, "data file corrupt for {}", date),
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Other(err) => write!(f, "{}", err),
+        }
     }
 }
 
-/// Build the template for a domain page (e.g. `/query.wikidata.org`)
-async fn build_domain(domain: String) -> Result<DomainTemplate, ErrorTemplate> {
-    let latest = match get_latest_data() {
-        Ok(latest) => latest,
-        Err(e) => {
-            return Err(ErrorTemplate {
-                error: e.to_string(),
-            })
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::RedisUnavailable(err) => Some(err),
+            Self::DataCorrupt { source, .. } => Some(source),
+            Self::Io(err) => Some(err),
+            _ => None,
         }
-    };
-    let client = match connect_redis() {
-        Ok(client) => client,
-        Err(err) => {
-            return Err(ErrorTemplate {
-                error: format!("redis error: {}", err),
-            });
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        // Recover the specific error if it was passed through a function
+        // returning anyhow::Result
+        let err = match err.downcast::<AppError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<redis::RedisError>() {
+            Ok(err) => return Self::RedisUnavailable(err),
+            Err(err) => err,
+        };
+        match err.downcast::<std::io::Error>() {
+            Ok(err) => Self::Io(err),
+            Err(err) => Self::Other(err),
         }
-    };
-    match get_data(latest, &client).await {
-        Ok(info) => {
-            for dinfo in info.stats {
-                if dinfo.domain == domain {
-                    return Ok(dinfo);
-                }
-            }
-            Err(ErrorTemplate {
-                error: "Unknown domain specified".to_string(),
-            })
+    }
+}
+
+impl From<redis::RedisError> for AppError {
+    fn from(err: redis::RedisError) -> Self {
+        Self::RedisUnavailable(err)
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Other(err.into())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for AppError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let (status, template) = (self.status(), self.template());
+        if status.class().is_server_error() {
+            error!(
+                "Error handling {}: {:#}",
+                req.uri(),
+                anyhow::Error::from(self)
+            );
         }
-        Err(e) => Err(ErrorTemplate {
-            error: e.to_string(),
-        }),
+        (status, Json(template)).respond_to(req)
     }
 }
 
-#[get("/api.json")]
-async fn index_api() -> Json<IndexTemplate> {
-    // FIXME: Error handling
-    match build_index().await {
-        Ok(index) => Json(index),
-        Err(error) => panic!("{}", error),
+/// How long clients may cache responses derived from the data files
+const CACHE_MAX_AGE: u32 = 60 * 60;
+
+/// Request guard describing when the data last changed, and when the
+/// client's cached copy (if any) is from
+struct Freshness {
+    last_modified: Option<DateTime<Utc>>,
+    if_modified_since: Option<DateTime<Utc>>,
+    etag: Option<String>,
+    if_none_match: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Freshness {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let if_modified_since = req
+            .headers()
+            .get_one("If-Modified-Since")
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .map(|since| since.with_timezone(&Utc));
+        // Listing the data directory and reading metadata block
+        let (latest, metadata) = rocket::tokio::task::spawn_blocking(|| {
+            let latest = get_latest_data().ok();
+            let metadata = latest
+                .as_deref()
+                .and_then(|latest| std::fs::metadata(latest).ok());
+            (latest, metadata)
+        })
+        .await
+        .unwrap_or_default();
+        request::Outcome::Success(Freshness {
+            last_modified: metadata.as_ref().and_then(last_modified),
+            if_modified_since,
+            etag: latest
+                .as_deref()
+                .map(|latest| etag(latest, metadata.as_ref(), &req.uri().to_string())),
+            if_none_match: req.headers().get_one("If-None-Match").map(String::from),
+        })
     }
 }
 
-#[get("/<domain>/api.json")]
-async fn domain_api(domain: String) -> Json<DomainTemplate> {
-    // FIXME: Error handling
-    match build_domain(domain).await {
-        Ok(dinfo) => Json(dinfo),
-        Err(error) => panic!("{}", error.error),
+impl Freshness {
+    /// Whether the client's cached copy is still up to date
+    fn is_not_modified(&self) -> bool {
+        // If-None-Match takes precedence over If-Modified-Since (RFC 7232)
+        if let Some(if_none_match) = &self.if_none_match {
+            return self
+                .etag
+                .as_deref()
+                .is_some_and(|etag| etag_matches(if_none_match, etag));
+        }
+        match (self.last_modified, self.if_modified_since) {
+            (Some(modified), Some(since)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Respond with `304 Not Modified`
+    fn not_modified<R>(&self) -> Cached<R> {
+        Cached {
+            inner: None,
+            last_modified: self.last_modified,
+            etag: self.etag.clone(),
+        }
+    }
+
+    /// Make the caching headers change at midnight too, for responses
+    /// that leave out data files from the cutoff date
+    fn with_cutoff(mut self, cutoff: Option<NaiveDate>) -> Self {
+        if let Some(cutoff) = cutoff {
+            let midnight = cutoff.and_hms_opt(0, 0, 0).unwrap().and_utc();
+            self.last_modified = self.last_modified.map(|modified| modified.max(midnight));
+            self.etag = self.etag.map(|etag| cutoff_etag(&etag, cutoff));
+        }
+        self
+    }
+
+    /// Attach caching headers to a response
+    fn wrap<R>(&self, inner: R) -> Cached<R> {
+        Cached {
+            inner: Some(inner),
+            last_modified: self.last_modified,
+            etag: self.etag.clone(),
+        }
     }
 }
 
-/// Build the index template (`/`)
-async fn build_index() -> Result<IndexTemplate> {
-    let latest = get_latest_data()?;
-    let client = connect_redis()?;
-    let mut data = get_data(latest, &client).await?;
-    // Hide domains with less than 10 short URLs
-    let stats = data
-        .stats
-        .into_iter()
-        .filter(|domain| domain.count >= 10)
-        .collect();
-    data.stats = stats;
-    Ok(data)
+/// Requests per minute each client may make to the expensive endpoints,
+/// unless overridden by `SHORTURLS_RATE_LIMIT`
+const DEFAULT_RATE_LIMIT: u32 = 60;
+
+/// Most clients to track at once. When there are this many, idle clients
+/// are forgotten, followed by the least recently seen ones if that doesn't
+/// free up a tenth of the space.
+const RATE_LIMIT_MAX_CLIENTS: usize = 10_000;
+
+/// Token bucket for a single client
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
 }
 
-/// get filename for the most recent data file
-fn get_latest_data() -> Result<PathBuf> {
-    find_data()?
-        .pop()
-        .ok_or_else(|| anyhow!("Could not find latest data"))
+/// In-memory, per-IP token bucket rate limiter
+struct RateLimiter {
+    /// Maximum burst size, refilled over a minute
+    per_minute: u32,
+    max_clients: usize,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
 }
 
-/// Get the data out of a data file, caching it in Redis if necessary
-async fn get_data(path: PathBuf, client: &redis::Client) -> Result<IndexTemplate> {
-    let cache_key = format!("shorturls:{}", path.to_str().unwrap());
-    let data = match client.get_async_connection().await {
-        Ok(mut conn) => {
-            let info: Option<String> = conn.get(&cache_key).await?;
-            if let Some(json) = info {
-                // If we can deserialize it, return , otherwise we'll just reread
-                // it from disk
-                if let Ok(val) = serde_json::from_str(&json) {
-                    return Ok(val);
-                }
+impl RateLimiter {
+    fn new(per_minute: u32, max_clients: usize) -> Self {
+        Self {
+            per_minute,
+            max_clients,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn from_env() -> Self {
+        Self::new(
+            rate_limit(std::env::var("SHORTURLS_RATE_LIMIT").ok()),
+            RATE_LIMIT_MAX_CLIENTS,
+        )
+    }
+
+    /// Take a token for the client, returning whether it's allowed through
+    fn check(&self, ip: IpAddr, now: Instant) -> bool {
+        let capacity = f64::from(self.per_minute);
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            (bucket.tokens + elapsed.as_secs_f64() * capacity / 60.0).min(capacity)
+        };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        if buckets.len() >= self.max_clients && !buckets.contains_key(&ip) {
+            // Clients with a full bucket are indistinguishable from new ones
+            buckets.retain(|_, bucket| refill(bucket) < capacity);
+            // Evict in bulk, so this doesn't happen again on every request
+            let target = self.max_clients - self.max_clients.div_ceil(10);
+            if buckets.len() > target {
+                let mut seen: Vec<Instant> =
+                    buckets.values().map(|bucket| bucket.updated).collect();
+                let (_, cutoff, _) = seen.select_nth_unstable(buckets.len() - target - 1);
+                let cutoff = *cutoff;
+                buckets.retain(|_, bucket| bucket.updated > cutoff);
             }
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Requests per minute to allow, from `SHORTURLS_RATE_LIMIT` if it's set to
+/// a positive number. A limit of 0 would reject every request, so it's
+/// ignored.
+fn rate_limit(configured: Option<String>) -> u32 {
+    configured
+        .and_then(|limit| limit.parse().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or(DEFAULT_RATE_LIMIT)
+}
 
-            let data: IndexTemplate = serde_json::from_str(&fs::read_to_string(&path).await?)?;
+/// Request guard for the expensive endpoints, failing with
+/// `429 Too Many Requests` once a client exceeds the rate limit
+struct RateLimit;
 
-            // Cache for 30 days
-            conn.set_ex(&cache_key, serde_json::to_string(&data)?, 60 * 60 * 24 * 30)
-                .await?;
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = ();
 
-            data
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let limiter = match req.rocket().state::<RateLimiter>() {
+            Some(limiter) => limiter,
+            None => return request::Outcome::Success(RateLimit),
+        };
+        match req.client_ip() {
+            Some(ip) if !limiter.check(ip, Instant::now()) => {
+                request::Outcome::Error((Status::TooManyRequests, ()))
+            }
+            _ => request::Outcome::Success(RateLimit),
         }
-        // Couldn't connect to redis, run without caching
-        Err(err) => {
-            dbg!(&err);
-            // XXX: Can we avoid duplication here?
-            serde_json::from_str(&fs::read_to_string(&path).await?)?
+    }
+}
+
+/// Responder that adds `Last-Modified`, `ETag` and `Cache-Control` headers
+/// to successful responses, or is a `304 Not Modified` if there's no response
+struct Cached<R> {
+    inner: Option<R>,
+    last_modified: Option<DateTime<Utc>>,
+    etag: Option<String>,
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for Cached<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = match self.inner {
+            Some(inner) => inner.respond_to(req)?,
+            None => Response::build().status(Status::NotModified).finalize(),
+        };
+        if let Some(modified) = self.last_modified {
+            if response.status().class().is_success() || response.status() == Status::NotModified {
+                response.set_raw_header("Last-Modified", http_date(modified));
+                response.set_raw_header(
+                    "Cache-Control",
+                    format!("public, max-age={}", CACHE_MAX_AGE),
+                );
+            }
         }
-    };
+        if let Some(etag) = self.etag {
+            if response.status().class().is_success() || response.status() == Status::NotModified {
+                response.set_raw_header("ETag", etag);
+            }
+        }
+        Ok(response)
+    }
+}
 
-    Ok(data)
+/// Responder that returns JSON if the client asks for it in the `Accept`
+/// header, and otherwise renders an HTML template
+struct Negotiated<T> {
+    template: &'static str,
+    result: Result<T, AppError>,
 }
 
-/// tera template helper to stick commas into large numbers
-fn commafy(args: &HashMap<String, Value>) -> TeraResult<Value> {
-    match args.get("num") {
-        Some(val) => Ok(val.separate_with_commas().into()),
-        None => Err("No value provided".into()),
+impl<'r, T: Serialize> Responder<'r, 'static> for Negotiated<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let wants_json = req
+            .accept()
+            .is_some_and(|accept| accept.preferred().is_json());
+        let mut response = match (self.result, wants_json) {
+            (Ok(val), true) => Json(val).respond_to(req)?,
+            (Ok(val), false) => Template::render(self.template, val).respond_to(req)?,
+            (Err(err), true) => err.respond_to(req)?,
+            (Err(err), false) => error_page(err).respond_to(req)?,
+        };
+        // The same URL has different representations
+        response.set_raw_header("Vary", "Accept");
+        Ok(response)
     }
 }
 
-/// parse the date out of data file names
-fn parse_date(fname: &str) -> Result<NaiveDate> {
-    Ok(NaiveDate::parse_from_str(
-        fname,
-        "shorturls-%Y%m%d.gz.data",
-    )?)
+/// When the latest data file was written, truncated to the second
+fn last_modified(metadata: &std::fs::Metadata) -> Option<DateTime<Utc>> {
+    let modified = metadata.modified().ok()?;
+    DateTime::from_timestamp(DateTime::<Utc>::from(modified).timestamp(), 0)
+}
+
+/// Entity tag for a response, which changes whenever there's a new data
+/// file, the data file is rewritten, or the request (including query
+/// parameters) is different
+fn etag(latest: &Path, metadata: Option<&std::fs::Metadata>, uri: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    uri.hash(&mut hasher);
+    if let Some(metadata) = metadata {
+        // Re-extracting a dump keeps the date but rewrites the file
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+    }
+    let date = latest
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| parse_date(name).ok())
+        .map_or_else(String::new, |date| date.format("%Y%m%d").to_string());
+    format!("\"{}-{:016x}\"", date, hasher.finish())
 }
 
-#[get("/chart.svg")]
-async fn chart_svg() -> (ContentType, String) {
-    (ContentType::SVG, chart2(None).await.unwrap())
+/// Entity tag that also changes with the cutoff date
+fn cutoff_etag(etag: &str, cutoff: NaiveDate) -> String {
+    format!(
+        "{}-{}\"",
+        etag.trim_end_matches('"'),
+        cutoff.format("%Y%m%d")
+    )
 }
 
-#[get("/<domain>/chart.svg")]
-async fn domain_chart_svg(domain: String) -> (ContentType, String) {
-    (ContentType::SVG, chart2(Some(&domain)).await.unwrap())
+/// Whether an `If-None-Match` header matches an entity tag, using the
+/// weak comparison since the tag is derived from the data file and
+/// request rather than the response body
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
 }
 
-/// Generate an SVG chart
-async fn chart2(domain: Option<&str>) -> Result<String> {
-    use plotters::prelude::*;
-    let mut buf = String::new();
-    {
-        let client = connect_redis()?;
+/// Format a timestamp for use in HTTP headers
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
 
-        let mut datapoints = Vec::new();
-        let mut domainpoints = Vec::new();
-        let mut chart_domain = Vec::new();
-        let mut final_total: f32 = 0.0;
-        for data in find_data()? {
-            let date = parse_date(data.file_name().unwrap().to_str().unwrap())?;
-            chart_domain.push(date);
-            let info = get_data(data, &client).await?;
-            datapoints.push((date, info.total as f32));
-            final_total = info.total as f32;
-            if let Some(host) = domain {
-                for dinfo in info.stats {
-                    if dinfo.domain == host {
-                        domainpoints.push((date, dinfo.count as f32));
-                        break;
-                    }
-                }
-            }
+/// Connect to `tools-redis`, or the server at `SHORTURLS_REDIS_URL`
+fn connect_redis() -> Result<redis::Client, AppError> {
+    Ok(redis::Client::open(redis_url(
+        std::env::var("SHORTURLS_REDIS_URL").ok(),
+        Path::new("/etc/wmcs-project").exists(),
+    ))?)
+}
+
+/// URL of the Redis server. A configured URL can include a database
+/// number and credentials, e.g. `redis://:password@host:6379/2`.
+fn redis_url(configured: Option<String>, on_toolforge: bool) -> String {
+    match configured {
+        Some(url) if !url.trim().is_empty() => url.trim().to_string(),
+        _ if on_toolforge => "redis://tools-redis:6379/".to_string(),
+        _ => "redis://127.0.0.1:6379/".to_string(),
+    }
+}
+
+/// Field to sort the index by
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum SortKey {
+    Count,
+    Domain,
+}
+
+/// Direction to sort the index in
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// How to group domains on the index
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum Grouping {
+    /// Each host separately
+    Domain,
+    /// Subdomains merged under their registrable domain
+    Parent,
+}
+
+/// Query parameters accepted by the index routes
+#[derive(FromForm, Default)]
+struct IndexOptions {
+    /// Defaults to not grouping at all
+    group: Option<Grouping>,
+    /// Only include the top N domains
+    limit: Option<usize>,
+    /// Hide domains with fewer short URLs than this, defaults to
+    /// `DEFAULT_MIN_COUNT`
+    min: Option<i32>,
+    /// Defaults to sorting by count
+    sort: Option<SortKey>,
+    /// Defaults to descending for counts and ascending for domains
+    order: Option<SortOrder>,
+}
+
+impl IndexOptions {
+    /// Check the parameters are sane, returning an error message if not
+    fn validate(&self) -> Result<(), String> {
+        if self.limit == Some(0) {
+            return Err("limit must be a positive number".to_string());
+        }
+        if self.min.is_some_and(|min| min < 1) {
+            return Err("min must be a positive number".to_string());
+        }
+        Ok(())
+    }
+
+    /// Smallest count a domain needs to be listed
+    fn min_count(&self) -> i32 {
+        self.min.unwrap_or(DEFAULT_MIN_COUNT)
+    }
+
+    /// Query string parameters that reproduce these options
+    fn query_pairs(&self) -> Vec<(&'static str, String)> {
+        let lower = |val: &dyn std::fmt::Debug| format!("{:?}", val).to_lowercase();
+        let mut pairs = vec![];
+        if let Some(group) = self.group {
+            pairs.push(("group", lower(&group)));
+        }
+        if let Some(limit) = self.limit {
+            pairs.push(("limit", limit.to_string()));
+        }
+        if let Some(min) = self.min {
+            pairs.push(("min", min.to_string()));
         }
+        if let Some(sort) = self.sort {
+            pairs.push(("sort", lower(&sort)));
+        }
+        if let Some(order) = self.order {
+            pairs.push(("order", lower(&order)));
+        }
+        pairs
+    }
+}
 
-        let start_date = chart_domain[0];
-        let end_date = chart_domain.last().unwrap();
+/// How many domains to show on each page of the index
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PerPage {
+    All,
+    Count(usize),
+}
 
-        let root_area = SVGBackend::with_string(&mut buf, (900, 300)).into_drawing_area();
-        root_area.fill(&WHITE)?;
-        let mut ctx = ChartBuilder::on(&root_area)
-            .set_label_area_size(LabelAreaPosition::Left, 60)
-            .set_label_area_size(LabelAreaPosition::Bottom, 60)
-            // Set the y-range from 0 to 105% of max so we don't cut off the top of the chart
-            .build_cartesian_2d(start_date..*end_date, 0.0..final_total * 1.05)?;
+impl<'v> FromFormField<'v> for PerPage {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        match field.value {
+            "all" => Ok(Self::All),
+            _ => usize::from_value(field).map(Self::Count),
+        }
+    }
+}
 
-        ctx.configure_mesh()
-            .disable_x_mesh()
-            .disable_y_mesh()
-            .draw()?;
+/// Domains per page when only `page` is given
+const DEFAULT_PER_PAGE: usize = 100;
 
-        ctx.draw_series(LineSeries::new(datapoints, &BLUE))?;
+/// The index, possibly cut down to a single page
+#[derive(Serialize)]
+struct IndexPage {
+    #[serde(flatten)]
+    index: IndexTemplate,
+    /// Number of short URLs that point to Wikimedia sites, counted
+    /// before the long tail is hidden
+    wikimedia_total: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pagination: Option<Pagination>,
+}
 
-        if domain.is_some() && !domainpoints.is_empty() {
-            ctx.draw_series(LineSeries::new(domainpoints, &GREEN))?;
+impl IndexPage {
+    fn new(index: IndexTemplate) -> Self {
+        Self {
+            wikimedia_total: index.count_wikimedia(),
+            index,
+            pagination: None,
         }
     }
-    Ok(buf)
 }
 
-#[get("/healthz")]
-fn healthz() -> &'static str {
-    "OK"
+/// Where a page is within the index
+#[derive(Serialize, Debug, PartialEq)]
+struct Pagination {
+    page: usize,
+    pages: usize,
+    per_page: usize,
+    /// Query string for the previous page, if there is one
+    prev: Option<String>,
+    /// Query string for the next page, if there is one
+    next: Option<String>,
+    /// Query string for everything on one page
+    all: String,
 }
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build()
-        .attach(Template::custom(|engines| {
-            engines.tera.register_function("commafy", Box::new(commafy));
-        }))
-        .mount(
-            "/",
-            routes![
-                index,
-                index_api,
-                chart_svg,
-                domain,
-                domain_api,
-                domain_chart_svg,
-                healthz,
-            ],
-        )
+/// Cut the index down to the requested page. Unless a page or number
+/// per page is given, everything is kept.
+fn paginate(
+    mut index: IndexPage,
+    opts: &IndexOptions,
+    page: Option<usize>,
+    per_page: Option<PerPage>,
+) -> Result<IndexPage, AppError> {
+    let per_page = match (page, per_page) {
+        (None, None) | (_, Some(PerPage::All)) => return Ok(index),
+        (_, Some(PerPage::Count(0))) => {
+            return Err(AppError::BadRequest(
+                "per_page must be a positive number or \"all\"".to_string(),
+            ))
+        }
+        (_, Some(PerPage::Count(per_page))) => per_page,
+        (Some(_), None) => DEFAULT_PER_PAGE,
+    };
+    let page = page.unwrap_or(1);
+    let pages = index.index.stats.len().div_ceil(per_page).max(1);
+    if page == 0 || page > pages {
+        return Err(AppError::NotFound(format!(
+            "No page {}, there are {}",
+            page, pages
+        )));
+    }
+    let link = |extra: &[(&'static str, String)]| {
+        let query: Vec<String> = opts
+            .query_pairs()
+            .iter()
+            .chain(extra)
+            .map(|(key, val)| format!("{}={}", key, val))
+            .collect();
+        format!("?{}", query.join("&"))
+    };
+    let page_link = |page: usize| {
+        link(&[
+            ("page", page.to_string()),
+            ("per_page", per_page.to_string()),
+        ])
+    };
+    let pagination = Pagination {
+        page,
+        pages,
+        per_page,
+        prev: (page > 1).then(|| page_link(page - 1)),
+        next: (page < pages).then(|| page_link(page + 1)),
+        all: link(&[("per_page", "all".to_string())]),
+    };
+    index.index.stats = index
+        .index
+        .stats
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .collect();
+    index.pagination = Some(pagination);
+    Ok(index)
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// Domains with fewer short URLs than this are hidden from the index
+/// unless a different `min` is given, so by default nothing is hidden
+const DEFAULT_MIN_COUNT: i32 = 1;
 
-    #[test]
-    fn test_commafy() {
-        let mut map: HashMap<String, Value> = HashMap::new();
-        map.insert("num".to_string(), Value::String("9999999".to_string()));
-        let result = commafy(&map);
-        assert_eq!(Value::String("\"9,999,999\"".to_string()), result.unwrap());
+#[get("/?<page>&<per_page>&<opts..>")]
+async fn index(
+    page: Option<usize>,
+    per_page: Option<PerPage>,
+    opts: IndexOptions,
+) -> Negotiated<IndexPage> {
+    let result = match opts.validate() {
+        Ok(()) => match build_index(&opts).await {
+            Ok(index) => paginate(index, &opts, page, per_page),
+            Err(err) => Err(err),
+        },
+        Err(error) => Err(AppError::BadRequest(error)),
+    };
+    Negotiated {
+        template: "main",
+        result,
+    }
+}
+
+#[get("/<domain>")]
+async fn domain(domain: String) -> Negotiated<DomainTemplate> {
+    Negotiated {
+        template: "domain",
+        result: build_domain(domain).await,
+    }
+}
+
+/// Build the template for a domain page (e.g. `/query.wikidata.org`)
+async fn build_domain(domain: String) -> Result<DomainTemplate, AppError> {
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    let mut conn = match redis_connection(&client).await {
+        Ok(conn) => conn,
+        // Couldn't connect to redis, scan the whole data file
+        Err(_) => return find_domain(get_data(latest, &client).await?, &domain),
+    };
+    let cache_key = domain_cache_key(&latest);
+    match lookup_domain(&mut conn, &cache_key, &domain).await {
+        Ok(CachedDomain::Found(dinfo)) => return Ok(dinfo),
+        Ok(CachedDomain::Unknown) => return Err(AppError::unknown_domain()),
+        Ok(CachedDomain::Missing) => {}
+        // Scan the whole data file instead
+        Err(err) => warn!("Unable to look up {} in Redis: {}", domain, err),
+    }
+    let data = get_data(latest.clone(), &client).await?;
+    let fields = data
+        .stats
+        .iter()
+        .map(|dinfo| Ok((dinfo.domain.as_str(), serde_json::to_string(dinfo)?)))
+        .collect::<Result<Vec<_>, serde_json::Error>>()?;
+    if !fields.is_empty() {
+        let filled = redis::pipe()
+            .atomic()
+            .hset_multiple(&cache_key, &fields)
+            .ignore()
+            .expire(&cache_key, cache_ttl(&latest))
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await;
+        if let Err(err) = filled {
+            warn!("Unable to cache domains in Redis: {}", err);
+        }
+    }
+    find_domain(data, &domain)
+}
+
+/// What the Redis hash of a data file's domains says about a domain
+enum CachedDomain {
+    Found(DomainTemplate),
+    /// The hash is there, but the domain isn't in the data file
+    Unknown,
+    /// The hash needs to be filled in
+    Missing,
+}
+
+/// Look a domain up in the Redis hash of a data file's domains
+async fn lookup_domain(
+    conn: &mut redis::aio::Connection,
+    cache_key: &str,
+    domain: &str,
+) -> redis::RedisResult<CachedDomain> {
+    let cached: Option<String> = conn.hget(cache_key, domain).await?;
+    let hash_exists = match cached {
+        Some(_) => true,
+        None => conn.exists(cache_key).await?,
+    };
+    Ok(cached_domain(cached, hash_exists))
+}
+
+/// Interpret a field from the domain hash, given whether the hash exists
+fn cached_domain(cached: Option<String>, hash_exists: bool) -> CachedDomain {
+    match cached.map(|json| serde_json::from_str(&json)) {
+        Some(Ok(dinfo)) => CachedDomain::Found(dinfo),
+        // Cached before the current format, so fill it in again
+        Some(Err(_)) => CachedDomain::Missing,
+        // The hash is always filled in all at once, so if it
+        // exists then the domain isn't in this data file
+        None if hash_exists => CachedDomain::Unknown,
+        None => CachedDomain::Missing,
+    }
+}
+
+/// Pick a single domain out of a data file
+fn find_domain(data: IndexTemplate, domain: &str) -> Result<DomainTemplate, AppError> {
+    data.stats
+        .into_iter()
+        .find(|dinfo| dinfo.domain == domain)
+        .ok_or_else(AppError::unknown_domain)
+}
+
+#[get("/api.json?<opts..>")]
+async fn index_api(
+    opts: IndexOptions,
+    fresh: Freshness,
+) -> Cached<Result<Json<IndexPage>, AppError>> {
+    if fresh.is_not_modified() {
+        return fresh.not_modified();
+    }
+    if let Err(error) = opts.validate() {
+        return fresh.wrap(Err(AppError::BadRequest(error)));
+    }
+    fresh.wrap(build_index(&opts).await.map(Json))
+}
+
+#[get("/<domain>/api.json")]
+async fn domain_api(
+    domain: String,
+    fresh: Freshness,
+) -> Cached<Result<Json<DomainTemplate>, AppError>> {
+    if fresh.is_not_modified() {
+        return fresh.not_modified();
+    }
+    fresh.wrap(build_domain(domain).await.map(Json))
+}
+
+// Ranked below the domain chart/history routes, which also match
+// two-segment paths
+#[get("/date/<date>?<page>&<per_page>&<opts..>", rank = 2)]
+async fn date_index(
+    date: &str,
+    page: Option<usize>,
+    per_page: Option<PerPage>,
+    opts: IndexOptions,
+) -> Result<Template, (Status, Template)> {
+    opts.validate()
+        .map_err(|error| error_page(AppError::BadRequest(error)))?;
+    let path = parse_path_date(date)
+        .and_then(data_for_date)
+        .map_err(error_page)?;
+    let index = build_index_from(path, &opts).await.map_err(error_page)?;
+    paginate(index, &opts, page, per_page)
+        .map(|page| Template::render("main", page))
+        .map_err(error_page)
+}
+
+#[get("/date/<date>/api.json?<opts..>")]
+async fn date_index_api(date: &str, opts: IndexOptions) -> Result<Json<IndexPage>, AppError> {
+    opts.validate().map_err(AppError::BadRequest)?;
+    let path = data_for_date(parse_path_date(date)?)?;
+    build_index_from(path, &opts).await.map(Json)
+}
+
+/// Parse a date given as YYYYMMDD in a URL path
+fn parse_path_date(date: &str) -> Result<NaiveDate, AppError> {
+    NaiveDate::parse_from_str(date, "%Y%m%d")
+        .map_err(|_| AppError::BadRequest(format!("Invalid date: {} (expected YYYYMMDD)", date)))
+}
+
+/// Find the data file for a date, with a 404 that mentions which
+/// dates are available if there isn't one
+fn data_for_date(date: NaiveDate) -> Result<PathBuf, AppError> {
+    let dated = dated_data()?;
+    if let Some((_, path)) = dated.iter().find(|(found, _)| *found == date) {
+        return Ok(path.clone());
+    }
+    let error = match (dated.first(), dated.last()) {
+        (Some((first, _)), Some((last, _))) => format!(
+            "No data for {}, data is available from {} to {}",
+            date, first, last
+        ),
+        _ => return Err(AppError::NoData),
+    };
+    Err(AppError::NotFound(error))
+}
+
+/// Render an error as an HTML error page with the same status
+fn error_page(err: AppError) -> (Status, Template) {
+    (err.status(), Template::render("error", err.template()))
+}
+
+/// Build the index template (`/`)
+async fn build_index(opts: &IndexOptions) -> Result<IndexPage, AppError> {
+    build_index_from(get_latest_data()?, opts).await
+}
+
+/// Build the index template out of a specific data file
+async fn build_index_from(path: PathBuf, opts: &IndexOptions) -> Result<IndexPage, AppError> {
+    let client = connect_redis()?;
+    let mut data = get_data(path, &client).await?;
+    if opts.group == Some(Grouping::Parent) {
+        data = data.grouped(|host| registrable_domain(host).unwrap_or(host).to_string());
+    }
+    let mut page = IndexPage::new(data);
+    let data = &mut page.index;
+    // Hide the long tail; total still counts them
+    let min = opts.min_count();
+    let stats = std::mem::take(&mut data.stats)
+        .into_iter()
+        .filter(|domain| domain.count >= min)
+        .take(opts.limit.unwrap_or(usize::MAX))
+        .collect();
+    data.stats = stats;
+    sort_stats(&mut data.stats, opts.sort, opts.order);
+    Ok(page)
+}
+
+/// Sort stats by the given field and direction. The data files are
+/// already sorted by count (descending), so that's the default.
+fn sort_stats(stats: &mut [DomainTemplate], sort: Option<SortKey>, order: Option<SortOrder>) {
+    let sort = sort.unwrap_or(SortKey::Count);
+    match sort {
+        SortKey::Count => stats.sort_by_key(|dinfo| std::cmp::Reverse(dinfo.count)),
+        SortKey::Domain => stats.sort_by_cached_key(|dinfo| dinfo.domain.to_lowercase()),
+    }
+    let default_order = match sort {
+        SortKey::Count => SortOrder::Desc,
+        SortKey::Domain => SortOrder::Asc,
+    };
+    if order.unwrap_or(default_order) != default_order {
+        stats.reverse();
+    }
+}
+
+/// get filename for the most recent data file
+fn get_latest_data() -> Result<PathBuf, AppError> {
+    find_data()?.pop().ok_or(AppError::NoData)
+}
+
+/// Get all the data files, optionally leaving out today's, which may
+/// have been written while the dump was still incomplete
+fn find_data_for_trend(exclude_today: bool) -> Result<Vec<PathBuf>> {
+    let files = find_data()?;
+    Ok(if exclude_today {
+        drop_date(files, Utc::now().date_naive())
+    } else {
+        files
+    })
+}
+
+/// Drop the latest data file if it's from the given date
+fn drop_date(mut files: Vec<PathBuf>, date: NaiveDate) -> Vec<PathBuf> {
+    let is_date = files
+        .last()
+        .and_then(|path| path.file_name()?.to_str())
+        .and_then(|name| parse_date(name).ok())
+        == Some(date);
+    if is_date {
+        files.pop();
+    }
+    files
+}
+
+/// Get all the data files along with their dates, oldest first
+fn dated_data() -> Result<Vec<(NaiveDate, PathBuf)>> {
+    find_data()?
+        .into_iter()
+        .map(|path| {
+            Ok((
+                parse_date(path.file_name().unwrap().to_str().unwrap())?,
+                path,
+            ))
+        })
+        .collect()
+}
+
+/// Default number of seconds to cache data files for (30 days)
+const DEFAULT_CACHE_TTL: usize = 60 * 60 * 24 * 30;
+
+/// Number of seconds to cache today's data file for, since it may still change
+const TODAY_CACHE_TTL: usize = 60 * 60;
+
+/// How long to cache a data file in Redis. Older data files never change, so
+/// they are cached for `SHORTURLS_CACHE_TTL` seconds (or 30 days by default),
+/// while today's file only gets a short TTL.
+fn cache_ttl(path: &Path) -> usize {
+    data_ttl(
+        path,
+        Utc::now().date_naive(),
+        std::env::var("SHORTURLS_CACHE_TTL").ok(),
+    )
+}
+
+/// `cache_ttl`, given today's date and the configured TTL (if any). Redis
+/// rejects a TTL of 0, so that's ignored.
+fn data_ttl(path: &Path, today: NaiveDate, configured: Option<String>) -> usize {
+    let date = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| parse_date(name).ok());
+    if date == Some(today) {
+        return TODAY_CACHE_TTL;
+    }
+    configured
+        .and_then(|ttl| ttl.parse().ok())
+        .filter(|ttl| *ttl > 0)
+        .unwrap_or(DEFAULT_CACHE_TTL)
+}
+
+/// Number of data files loaded from Redis
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+/// Number of data files that weren't in Redis and had to be read from disk
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// A data file's name plus when it was written, for cache keys. Re-extracting
+/// a file (`extract-data --force`) changes its mtime, so nothing cached from
+/// the old copy is served for the new one.
+fn data_version(path: &Path) -> String {
+    let name = path.file_name().unwrap().to_str().unwrap();
+    match std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => format!(
+            "{}@{}",
+            name,
+            DateTime::<Utc>::from(modified).timestamp_millis()
+        ),
+        Err(_) => name.to_string(),
+    }
+}
+
+/// Redis key a data file is cached under
+fn data_cache_key(path: &Path) -> String {
+    format!(
+        "shorturls:{}",
+        path.with_file_name(data_version(path)).to_str().unwrap()
+    )
+}
+
+/// Redis key of the hash holding each domain's entry in a data file,
+/// so domain pages don't have to load the whole file
+fn domain_cache_key(path: &Path) -> String {
+    format!("shorturls:domains:{}", data_version(path))
+}
+
+/// Number of times to try connecting to Redis before running uncached
+const REDIS_ATTEMPTS: u32 = 3;
+
+/// How long to wait before the first retry, doubling after that
+const REDIS_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// After giving up on Redis, how long to only try connecting once, so
+/// an outage doesn't slow every request down by the retries
+const REDIS_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Tracks whether Redis is assumed to be down
+struct RedisHealth {
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl RedisHealth {
+    const fn new() -> Self {
+        Self {
+            down_until: Mutex::new(None),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Instant>> {
+        // The state is a single timestamp, so it's fine to keep using it
+        // after a panic
+        self.down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// How many times to try connecting
+    fn attempts(&self, now: Instant) -> u32 {
+        match *self.lock() {
+            Some(until) if now < until => 1,
+            _ => REDIS_ATTEMPTS,
+        }
+    }
+
+    fn connected(&self) {
+        *self.lock() = None;
+    }
+
+    /// Give up on Redis for the cooldown period
+    fn failed(&self, now: Instant) {
+        *self.lock() = Some(now + REDIS_COOLDOWN);
+    }
+}
+
+static REDIS_HEALTH: RedisHealth = RedisHealth::new();
+
+/// How long to wait before the given retry (starting from 1)
+fn redis_retry_delay(retry: u32) -> Duration {
+    REDIS_RETRY_DELAY * 2u32.pow(retry - 1)
+}
+
+/// Connect to Redis, retrying with exponential backoff to ride out
+/// brief blips
+async fn redis_connection(client: &redis::Client) -> redis::RedisResult<redis::aio::Connection> {
+    connect_with_retries(&REDIS_HEALTH, || client.get_async_connection()).await
+}
+
+/// Try to connect as many times as `health` allows, backing off between
+/// attempts and starting a cooldown if they all fail
+async fn connect_with_retries<T, E, F, Fut>(health: &RedisHealth, mut connect: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let attempts = health.attempts(Instant::now());
+    let mut retry = 0;
+    loop {
+        match connect().await {
+            Ok(conn) => {
+                health.connected();
+                return Ok(conn);
+            }
+            Err(err) if retry + 1 >= attempts => {
+                if attempts > 1 {
+                    warn!(
+                        "Unable to connect to Redis after {} attempts, running without caching: {}",
+                        attempts, err
+                    );
+                    health.failed(Instant::now());
+                }
+                return Err(err);
+            }
+            Err(_) => {
+                retry += 1;
+                rocket::tokio::time::sleep(redis_retry_delay(retry)).await;
+            }
+        }
+    }
+}
+
+/// Get the data out of a data file, caching it in Redis if necessary
+async fn get_data(path: PathBuf, client: &redis::Client) -> Result<IndexTemplate, AppError> {
+    let cache_key = data_cache_key(&path);
+    let mut data = match redis_connection(client).await {
+        Ok(mut conn) => {
+            let info: Option<String> = conn.get(&cache_key).await?;
+            // If we can deserialize it, use it, otherwise we'll just reread
+            // it from disk
+            let cached: Option<IndexTemplate> =
+                info.and_then(|json| serde_json::from_str(&json).ok());
+            let (mut data, cache_hit) = match cached {
+                Some(val) => {
+                    CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+                    (val, true)
+                }
+                None => {
+                    CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+                    (read_data(&path).await?, false)
+                }
+            };
+            // Also re-cache anything cached before the current format
+            if migrate_data(&mut data, &path) || !cache_hit {
+                conn.set_ex::<_, _, ()>(
+                    &cache_key,
+                    serde_json::to_string(&data)?,
+                    cache_ttl(&path),
+                )
+                .await?;
+            }
+
+            data
+        }
+        // Couldn't connect to redis, run without caching
+        Err(_) => {
+            let mut data = read_data(&path).await?;
+            migrate_data(&mut data, &path);
+            data
+        }
+    };
+    data.compute_percentages();
+    data.compute_ranks();
+    data.decode_hosts();
+    data.distinct_domains = data.stats.len();
+
+    Ok(data)
+}
+
+/// Read a data file from disk
+async fn read_data(path: &Path) -> Result<IndexTemplate, AppError> {
+    let contents = fs::read_to_string(path).await?;
+    serde_json::from_str(&contents).map_err(|err| AppError::corrupt(path, err))
+}
+
+/// Upgrade data to the current format, returning whether it changed
+fn migrate_data(data: &mut IndexTemplate, path: &Path) -> bool {
+    if data.schema_version > SCHEMA_VERSION {
+        warn!(
+            "{} has schema version {}, but only up to {} is supported",
+            path.display(),
+            data.schema_version,
+            SCHEMA_VERSION
+        );
+    }
+    data.migrate()
+}
+
+/// tera template helper to stick commas (or another separator given
+/// as `sep`, e.g. a space or period) into large numbers
+fn commafy(args: &HashMap<String, Value>) -> TeraResult<Value> {
+    let separator = match args.get("sep") {
+        Some(Value::String(sep)) => sep.as_str(),
+        Some(val) => return Err(format!("Not a separator: {}", val).into()),
+        None => ",",
+    };
+    let policy = SeparatorPolicy {
+        separator,
+        groups: &[3],
+        digits: thousands::digits::ASCII_DECIMAL,
+    };
+    match args.get("num") {
+        Some(Value::Number(num)) => Ok(num.separate_by_policy(policy).into()),
+        // Format the string itself, not its JSON representation
+        Some(Value::String(num)) => Ok(num.separate_by_policy(policy).into()),
+        Some(val) => Err(format!("Not a number: {}", val).into()),
+        None => Err("No value provided".into()),
+    }
+}
+
+/// parse the date out of data file names
+fn parse_date(fname: &str) -> Result<NaiveDate> {
+    DATA_FILE_PATTERNS
+        .iter()
+        .find_map(|pattern| NaiveDate::parse_from_str(fname, pattern).ok())
+        .ok_or_else(|| anyhow!("Unrecognized data file name: {}", fname))
+}
+
+/// Names of data files, one for each dump compression format
+const DATA_FILE_PATTERNS: &[&str] = &["shorturls-%Y%m%d.gz.data", "shorturls-%Y%m%d.bz2.data"];
+
+/// A rendered chart, or an error if it couldn't be generated
+type ChartResponse<T> = Result<Cached<(ContentType, T)>, AppError>;
+
+#[get("/chart.svg?<opts..>")]
+async fn chart_svg(
+    opts: ChartOptions,
+    fresh: Freshness,
+    _limit: RateLimit,
+) -> ChartResponse<String> {
+    opts.validate()?;
+    let fresh = fresh.with_cutoff(opts.cutoff(Utc::now().date_naive()));
+    if fresh.is_not_modified() {
+        return Ok(fresh.not_modified());
+    }
+    let svg = chart2(None, &opts).await?;
+    Ok(fresh.wrap((ContentType::SVG, svg)))
+}
+
+#[get("/<domain>/chart.svg?<opts..>")]
+async fn domain_chart_svg(
+    domain: String,
+    opts: ChartOptions,
+    fresh: Freshness,
+    _limit: RateLimit,
+) -> ChartResponse<String> {
+    opts.validate()?;
+    let fresh = fresh.with_cutoff(opts.cutoff(Utc::now().date_naive()));
+    if fresh.is_not_modified() {
+        return Ok(fresh.not_modified());
+    }
+    let svg = chart2(Some(&domain), &opts).await?;
+    Ok(fresh.wrap((ContentType::SVG, svg)))
+}
+
+#[get("/chart.png?<opts..>")]
+async fn chart_png(
+    opts: ChartOptions,
+    fresh: Freshness,
+    _limit: RateLimit,
+) -> ChartResponse<Vec<u8>> {
+    opts.validate()?;
+    let fresh = fresh.with_cutoff(opts.cutoff(Utc::now().date_naive()));
+    if fresh.is_not_modified() {
+        return Ok(fresh.not_modified());
+    }
+    let png = chart2_png(None, &opts).await?;
+    Ok(fresh.wrap((ContentType::PNG, png)))
+}
+
+#[get("/<domain>/chart.png?<opts..>")]
+async fn domain_chart_png(
+    domain: String,
+    opts: ChartOptions,
+    fresh: Freshness,
+    _limit: RateLimit,
+) -> ChartResponse<Vec<u8>> {
+    opts.validate()?;
+    let fresh = fresh.with_cutoff(opts.cutoff(Utc::now().date_naive()));
+    if fresh.is_not_modified() {
+        return Ok(fresh.not_modified());
+    }
+    let png = chart2_png(Some(&domain), &opts).await?;
+    Ok(fresh.wrap((ContentType::PNG, png)))
+}
+
+#[get("/chart/grid.svg?<domains>&<opts..>")]
+async fn chart_grid_svg(
+    domains: Option<&str>,
+    opts: ChartOptions,
+    fresh: Freshness,
+    _limit: RateLimit,
+) -> ChartResponse<String> {
+    opts.validate()?;
+    let names = parse_domain_list(domains, MAX_GRID_PANELS)?;
+    let fresh = fresh.with_cutoff(opts.cutoff(Utc::now().date_naive()));
+    if fresh.is_not_modified() {
+        return Ok(fresh.not_modified());
+    }
+    let svg = chart_grid(&names, &opts).await?;
+    Ok(fresh.wrap((ContentType::SVG, svg)))
+}
+
+/// The series a chart draws, as `[date, value]` pairs
+#[derive(Serialize)]
+struct ChartSeriesTemplate {
+    total: Vec<(String, i64)>,
+    /// Only present for domain charts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<Vec<(String, i64)>>,
+}
+
+impl ChartSeriesTemplate {
+    /// Apply the chart options to a series, using the exact counts rather
+    /// than the `f32` points that are drawn
+    fn new(series: ChartSeries, opts: &ChartOptions, domain: Option<&str>) -> Self {
+        let interval = opts.interval.unwrap_or(Interval::Day);
+        let widen = |points: Vec<(NaiveDate, i32)>| {
+            points
+                .into_iter()
+                .map(|(date, count)| (date, i64::from(count)))
+                .collect()
+        };
+        let (total, domainpoints) = match opts.metric.unwrap_or(Metric::Total) {
+            Metric::Total => (widen(series.total), widen(series.domain)),
+            Metric::AvgPerDomain => (
+                series
+                    .average_per_domain()
+                    .into_iter()
+                    .map(|(date, avg)| (date, avg.round() as i64))
+                    .collect(),
+                vec![],
+            ),
+        };
+        let pairs = |points: Vec<(NaiveDate, i64)>| {
+            downsample(points, interval)
+                .into_iter()
+                .map(|(date, value)| (date.format("%Y-%m-%d").to_string(), value))
+                .collect()
+        };
+        Self {
+            total: pairs(total),
+            domain: domain.map(|_| pairs(domainpoints)),
+        }
+    }
+}
+
+#[get("/chart.json?<opts..>")]
+async fn chart_json(
+    opts: ChartOptions,
+    fresh: Freshness,
+    _limit: RateLimit,
+) -> Cached<Result<Json<ChartSeriesTemplate>, AppError>> {
+    if let Err(err) = opts.validate() {
+        return fresh.wrap(Err(err));
+    }
+    let fresh = fresh.with_cutoff(opts.cutoff(Utc::now().date_naive()));
+    if fresh.is_not_modified() {
+        return fresh.not_modified();
+    }
+    fresh.wrap(
+        build_chart_series(None, &opts)
+            .await
+            .map(Json)
+            .map_err(AppError::from),
+    )
+}
+
+#[get("/<domain>/chart.json?<opts..>")]
+async fn domain_chart_json(
+    domain: String,
+    opts: ChartOptions,
+    fresh: Freshness,
+    _limit: RateLimit,
+) -> Cached<Result<Json<ChartSeriesTemplate>, AppError>> {
+    if let Err(err) = opts.validate() {
+        return fresh.wrap(Err(err));
+    }
+    let fresh = fresh.with_cutoff(opts.cutoff(Utc::now().date_naive()));
+    if fresh.is_not_modified() {
+        return fresh.not_modified();
+    }
+    fresh.wrap(
+        build_chart_series(Some(&domain), &opts)
+            .await
+            .map(Json)
+            .map_err(AppError::from),
+    )
+}
+
+/// Get the series that would be drawn on a chart, without drawing it
+async fn build_chart_series(
+    domain: Option<&str>,
+    opts: &ChartOptions,
+) -> Result<ChartSeriesTemplate> {
+    let series = chart_series(domain, opts).await?;
+    Ok(ChartSeriesTemplate::new(series, opts, domain))
+}
+
+/// Scale to use for the y-axis of charts
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum Scale {
+    Linear,
+    Log,
+}
+
+/// Query parameters accepted by the chart routes
+#[derive(FromForm, Default)]
+struct ChartOptions {
+    width: Option<u32>,
+    height: Option<u32>,
+    scale: Option<Scale>,
+    interval: Option<Interval>,
+    theme: Option<Theme>,
+    /// Hex color (e.g. `ff8800`) for the total line, overriding the theme
+    color: Option<String>,
+    overlay: Option<Overlay>,
+    /// Leave out today's data file, which may be incomplete
+    exclude_today: Option<bool>,
+    /// Defaults to the total
+    metric: Option<Metric>,
+    /// Only draw points on or after this date (YYYY-MM-DD)
+    since: Option<String>,
+}
+
+/// What to draw as the main line of a chart
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum Metric {
+    /// Total number of short URLs
+    Total,
+    /// Mean number of short URLs per distinct domain. Domain counts
+    /// aren't comparable to it, so they aren't drawn.
+    #[field(value = "avg_per_domain")]
+    AvgPerDomain,
+}
+
+impl Metric {
+    /// Description of the y-axis
+    fn label(self) -> &'static str {
+        match self {
+            Metric::Total => "Short URLs",
+            Metric::AvgPerDomain => "Short URLs per domain",
+        }
+    }
+}
+
+/// Extra series that can be drawn on top of the total
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum Overlay {
+    /// Change in the total since the previous point, on a secondary axis
+    Growth,
+}
+
+/// Color scheme for charts
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum Theme {
+    Light,
+    Dark,
+}
+
+/// Colors used to draw a chart
+struct Palette {
+    background: RGBColor,
+    /// Axes and labels
+    foreground: RGBColor,
+    total: RGBColor,
+    domain: RGBColor,
+    growth: RGBColor,
+}
+
+impl Theme {
+    fn palette(self) -> Palette {
+        match self {
+            Theme::Light => Palette {
+                background: WHITE,
+                foreground: BLACK,
+                total: BLUE,
+                domain: GREEN,
+                growth: RED,
+            },
+            Theme::Dark => Palette {
+                background: RGBColor(0x20, 0x22, 0x25),
+                foreground: RGBColor(0xea, 0xec, 0xf0),
+                total: RGBColor(0x6b, 0xa4, 0xff),
+                domain: RGBColor(0x5c, 0xd6, 0x8a),
+                growth: RGBColor(0xff, 0x8a, 0x65),
+            },
+        }
+    }
+}
+
+/// Parse a hex color like `ff8800` or `#ff8800`
+fn parse_color(color: &str) -> Option<RGBColor> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |idx: usize| u8::from_str_radix(&hex[idx..idx + 2], 16).ok();
+    Some(RGBColor(channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Bucket size to downsample chart series into
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum Interval {
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    /// Key identifying which bucket a date falls into
+    fn bucket(self, date: NaiveDate) -> (i32, u32) {
+        match self {
+            Interval::Day => (date.year(), date.ordinal()),
+            Interval::Week => (date.iso_week().year(), date.iso_week().week()),
+            Interval::Month => (date.year(), date.month()),
+        }
+    }
+}
+
+/// Difference between each point and the one before it
+fn growth_points(points: &[(NaiveDate, f32)]) -> Vec<(NaiveDate, f32)> {
+    points
+        .windows(2)
+        .map(|pair| (pair[1].0, pair[1].1 - pair[0].1))
+        .collect()
+}
+
+/// Reduce a series to one point per bucket, keeping the last value in each
+fn downsample<T>(points: Vec<(NaiveDate, T)>, interval: Interval) -> Vec<(NaiveDate, T)> {
+    let mut sampled: Vec<(NaiveDate, T)> = Vec::new();
+    for point in points {
+        match sampled.last_mut() {
+            Some(last) if interval.bucket(last.0) == interval.bucket(point.0) => *last = point,
+            _ => sampled.push(point),
+        }
+    }
+    sampled
+}
+
+impl ChartOptions {
+    /// Check the options that can't be validated by their type
+    fn validate(&self) -> Result<(), AppError> {
+        self.since.as_deref().map(parse_query_date).transpose()?;
+        Ok(())
+    }
+
+    /// Date whose data file is left out, if any
+    fn cutoff(&self, today: NaiveDate) -> Option<NaiveDate> {
+        (self.exclude_today == Some(true)).then_some(today)
+    }
+
+    /// Start of the chart, if one was given. Anything before the
+    /// earliest data file has the same effect as not passing one.
+    fn since_date(&self) -> Option<NaiveDate> {
+        self.since
+            .as_deref()
+            .and_then(|since| parse_query_date(since).ok())
+    }
+
+    /// Colors to draw with, from the theme and any explicit color
+    fn palette(&self) -> Palette {
+        let mut palette = self.theme.unwrap_or(Theme::Light).palette();
+        if let Some(color) = self.color.as_deref().and_then(parse_color) {
+            palette.total = color;
+        }
+        palette
+    }
+
+    /// Chart dimensions, clamping any user-provided values
+    /// to sane bounds and falling back to the defaults
+    fn size(&self) -> (u32, u32) {
+        (
+            self.width
+                .map_or(CHART_SIZE.0, |width| width.clamp(200, 4000)),
+            self.height
+                .map_or(CHART_SIZE.1, |height| height.clamp(200, 4000)),
+        )
+    }
+}
+
+/// Default width and height of generated charts
+const CHART_SIZE: (u32, u32) = (900, 300);
+
+/// Data points to be drawn on a chart
+struct ChartPoints {
+    dates: Vec<NaiveDate>,
+    datapoints: Vec<(NaiveDate, f32)>,
+    domainpoints: Vec<(NaiveDate, f32)>,
+    /// Only filled in when the growth overlay was requested
+    growthpoints: Vec<(NaiveDate, f32)>,
+    /// Top of the y-axis, before leaving some space above it
+    final_total: f32,
+    metric: Metric,
+}
+
+/// How many data files to load at once while gathering a chart's series
+const CHART_LOAD_CONCURRENCY: usize = 8;
+
+/// Collect the series for a chart from all the data files,
+/// optionally starting at a specific date
+async fn gather_series(
+    domain: Option<&str>,
+    exclude_today: bool,
+    since: Option<NaiveDate>,
+) -> Result<ChartSeries> {
+    use rocket::futures::{stream, StreamExt, TryStreamExt};
+    let client = connect_redis()?;
+    let mut files = vec![];
+    for data in find_data_for_trend(exclude_today)? {
+        let date = parse_date(data.file_name().unwrap().to_str().unwrap())?;
+        if since.is_some_and(|since| date < since) {
+            continue;
+        }
+        files.push((date, data));
+    }
+    let client = &client;
+    let mut loaded: Vec<(NaiveDate, ChartSeries)> = stream::iter(files)
+        .map(|(date, data)| async move {
+            let mut point = ChartSeries::default();
+            match get_data(data, client).await {
+                Ok(info) => point.push(date, &info, domain),
+                // One bad file shouldn't take down the whole chart
+                Err(err @ AppError::DataCorrupt { .. }) => {
+                    warn!("Skipping in chart: {:#}", anyhow::Error::from(err));
+                }
+                Err(err) => return Err(err),
+            }
+            Ok((date, point))
+        })
+        .buffer_unordered(CHART_LOAD_CONCURRENCY)
+        .try_collect()
+        .await?;
+    // Files finish loading in any order, but the series has to be by date
+    loaded.sort_by_key(|(date, _)| *date);
+    let mut series = ChartSeries::default();
+    for (_, point) in loaded {
+        series.append(point);
+    }
+    Ok(series)
+}
+
+/// Collect the series for a chart, limited to the files the options ask for
+async fn chart_series(domain: Option<&str>, opts: &ChartOptions) -> Result<ChartSeries> {
+    gather_series(
+        domain,
+        opts.exclude_today.unwrap_or(false),
+        opts.since_date(),
+    )
+    .await
+}
+
+/// Get the data points to draw for a chart
+async fn chart_points(domain: Option<&str>, opts: &ChartOptions) -> Result<ChartPoints> {
+    Ok(ChartPoints::new(chart_series(domain, opts).await?, opts))
+}
+
+impl ChartPoints {
+    /// Prepare a series for drawing, applying the chart options
+    fn new(series: ChartSeries, opts: &ChartOptions) -> Self {
+        let interval = opts.interval.unwrap_or(Interval::Day);
+        let as_points = |points: Vec<(NaiveDate, i32)>| {
+            points
+                .into_iter()
+                .map(|(date, count)| (date, count as f32))
+                .collect()
+        };
+        let metric = opts.metric.unwrap_or(Metric::Total);
+        let dates = series.dates();
+        let (final_total, datapoints, domainpoints) = match metric {
+            Metric::Total => (
+                series.total.last().map_or(0.0, |(_, total)| *total as f32),
+                as_points(series.total),
+                as_points(series.domain),
+            ),
+            Metric::AvgPerDomain => {
+                let averages = series.average_per_domain();
+                let top = averages.iter().fold(0.0f32, |top, (_, avg)| top.max(*avg));
+                (top, averages, vec![])
+            }
+        };
+        let datapoints = downsample(datapoints, interval);
+        let growthpoints = match opts.overlay {
+            Some(Overlay::Growth) => growth_points(&datapoints),
+            None => vec![],
+        };
+        ChartPoints {
+            dates,
+            datapoints,
+            domainpoints: downsample(domainpoints, interval),
+            growthpoints,
+            final_total,
+            metric,
+        }
+    }
+}
+
+/// Register the embedded font with plotters, so we don't
+/// depend on whatever system fonts are installed
+fn register_fonts() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        if plotters::style::register_font(
+            "sans-serif",
+            plotters::style::FontStyle::Normal,
+            include_bytes!("../fonts/DejaVuSans.ttf"),
+        )
+        .is_err()
+        {
+            error!("Unable to load embedded font");
+        }
+    });
+}
+
+/// Draw a chart onto the provided drawing area, regardless of backend
+fn draw_chart<DB>(
+    root_area: &DrawingArea<DB, Shift>,
+    mut points: ChartPoints,
+    caption: &str,
+    opts: &ChartOptions,
+) -> Result<()>
+where
+    DB: DrawingBackend,
+    DB::ErrorType: 'static,
+{
+    use plotters::{
+        prelude::*,
+        style::text_anchor::{HPos, Pos, VPos},
+    };
+    register_fonts();
+
+    let palette = opts.palette();
+    root_area.fill(&palette.background)?;
+    let (start_date, end_date) = match (points.dates.first(), points.dates.last()) {
+        (Some(start), Some(end)) => (*start, *end),
+        // Nothing has been extracted yet, so show a placeholder instead
+        _ => {
+            let (width, height) = root_area.dim_in_pixel();
+            root_area.draw(&Text::new(
+                NO_DATA,
+                (width as i32 / 2, height as i32 / 2),
+                ("sans-serif", 20)
+                    .into_font()
+                    .color(&palette.foreground)
+                    .pos(Pos::new(HPos::Center, VPos::Center)),
+            ))?;
+            root_area.present()?;
+            return Ok(());
+        }
+    };
+    match opts.scale.unwrap_or(Scale::Linear) {
+        // Set the y-range from 0 to 105% of max so we don't cut off the top of the chart
+        Scale::Linear => draw_series(
+            root_area,
+            start_date..end_date,
+            0.0..points.final_total * 1.05,
+            points,
+            caption,
+            &palette,
+        ),
+        Scale::Log => {
+            // Logarithms of zero or negative numbers are undefined, so drop
+            // any such points and make sure the range starts at 1
+            points.datapoints.retain(|(_, total)| *total > 0.0);
+            points.domainpoints.retain(|(_, count)| *count > 0.0);
+            let top = (points.final_total * 1.05).max(10.0);
+            draw_series(
+                root_area,
+                start_date..end_date,
+                (1.0..top).log_scale(),
+                points,
+                caption,
+                &palette,
+            )
+        }
+    }
+}
+
+/// Draw the axes and series of a chart with the given y-axis coordinates
+fn draw_series<DB, Y>(
+    root_area: &DrawingArea<DB, Shift>,
+    x_range: std::ops::Range<NaiveDate>,
+    y_range: Y,
+    points: ChartPoints,
+    caption: &str,
+    palette: &Palette,
+) -> Result<()>
+where
+    DB: DrawingBackend,
+    DB::ErrorType: 'static,
+    Y: plotters::coord::ranged1d::AsRangedCoord<Value = f32>,
+    Y::CoordDescType: plotters::coord::ranged1d::ValueFormatter<f32>,
+{
+    use plotters::{prelude::*, style::SizeDesc};
+    let mut builder = ChartBuilder::on(root_area);
+    builder
+        .caption(
+            caption,
+            ("sans-serif", 20).into_font().color(&palette.foreground),
+        )
+        .set_label_area_size(LabelAreaPosition::Left, 80)
+        .set_label_area_size(LabelAreaPosition::Bottom, 60);
+    if !points.growthpoints.is_empty() {
+        builder.set_label_area_size(LabelAreaPosition::Right, 80);
+    }
+    let mut ctx = builder.build_cartesian_2d(x_range.clone(), y_range)?;
+
+    // Same size plotters uses by default
+    let label_size = f64::from(
+        12.percent()
+            .max(12)
+            .in_pixels(&ctx.plotting_area().dim_in_pixel()),
+    );
+    let label_style = ("sans-serif", label_size)
+        .into_font()
+        .color(&palette.foreground);
+    ctx.configure_mesh()
+        .disable_x_mesh()
+        .disable_y_mesh()
+        .axis_style(palette.foreground)
+        .label_style(label_style.clone())
+        .axis_desc_style(label_style.clone())
+        .x_desc("Date")
+        .y_desc(points.metric.label())
+        .draw()?;
+
+    ctx.draw_series(LineSeries::new(points.datapoints, &palette.total))?;
+
+    if !points.domainpoints.is_empty() {
+        ctx.draw_series(LineSeries::new(points.domainpoints, &palette.domain))?;
+    }
+
+    if !points.growthpoints.is_empty() {
+        let (low, high) = points
+            .growthpoints
+            .iter()
+            .fold((0.0f32, 0.0f32), |(low, high), (_, delta)| {
+                (low.min(*delta), high.max(*delta))
+            });
+        let mut ctx = ctx.set_secondary_coord(x_range, low * 1.05..(high * 1.05).max(1.0));
+        ctx.configure_secondary_axes()
+            .axis_style(palette.foreground)
+            .label_style(label_style.clone())
+            .axis_desc_style(label_style)
+            .y_desc("Growth")
+            .draw()?;
+        ctx.draw_secondary_series(LineSeries::new(points.growthpoints, &palette.growth))?;
+    }
+    root_area.present()?;
+    Ok(())
+}
+
+/// Generate an SVG chart
+async fn chart2(domain: Option<&str>, opts: &ChartOptions) -> Result<String> {
+    use plotters::prelude::*;
+    // The latest data file is part of the key, so the cached chart
+    // is replaced as soon as a new dump is processed. Without any
+    // data there's nothing worth caching.
+    let latest = find_data()?.pop();
+    let cache_key = latest
+        .as_ref()
+        .map(|latest| chart_cache_key(domain, opts, latest, Utc::now().date_naive()));
+    let client = connect_redis()?;
+    let mut conn = match cache_key {
+        Some(_) => redis_connection(&client).await.ok(),
+        None => None,
+    };
+    if let (Some(conn), Some(cache_key)) = (conn.as_mut(), cache_key.as_ref()) {
+        // Treat a failed lookup as a cache miss
+        let cached: Option<String> = conn.get(cache_key).await.unwrap_or(None);
+        if let Some(svg) = cached {
+            return Ok(svg);
+        }
+    }
+    let points = chart_points(domain, opts).await?;
+    let mut buf = String::new();
+    {
+        let root_area = SVGBackend::with_string(&mut buf, opts.size()).into_drawing_area();
+        draw_chart(&root_area, points, &chart_caption(domain, opts), opts)?;
+    }
+    if let (Some(conn), Some(cache_key), Some(latest)) = (conn.as_mut(), cache_key, latest) {
+        if let Err(err) = conn
+            .set_ex::<_, _, ()>(&cache_key, &buf, cache_ttl(&latest))
+            .await
+        {
+            warn!("Unable to cache chart: {}", err);
+        }
+    }
+    Ok(buf)
+}
+
+/// Most panels that can be drawn in a single grid chart
+const MAX_GRID_PANELS: usize = 16;
+
+/// Default size of each panel in a grid chart
+const GRID_PANEL_SIZE: (u32, u32) = (450, 250);
+
+/// How many panels of a grid chart to gather at once
+const GRID_PANEL_CONCURRENCY: usize = 4;
+
+/// Generate an SVG with a small chart for each domain, laid out in a grid
+async fn chart_grid(domains: &[&str], opts: &ChartOptions) -> Result<String> {
+    use plotters::prelude::*;
+    use rocket::futures::{stream, StreamExt, TryStreamExt};
+    let latest = find_data()?.pop();
+    let cache_key = latest.as_ref().map(|latest| {
+        chart_cache_key(
+            Some(&format!("grid:{}", domains.join(","))),
+            opts,
+            latest,
+            Utc::now().date_naive(),
+        )
+    });
+    let client = connect_redis()?;
+    let mut conn = match cache_key {
+        Some(_) => redis_connection(&client).await.ok(),
+        None => None,
+    };
+    if let (Some(conn), Some(cache_key)) = (conn.as_mut(), cache_key.as_ref()) {
+        // Treat a failed lookup as a cache miss
+        let cached: Option<String> = conn.get(cache_key).await.unwrap_or(None);
+        if let Some(svg) = cached {
+            return Ok(svg);
+        }
+    }
+    // Each panel loads its own data files concurrently too
+    let loads: Vec<_> = domains
+        .iter()
+        .map(|domain| chart_points(Some(domain), opts))
+        .collect();
+    let panels: Vec<ChartPoints> = stream::iter(loads)
+        .buffered(GRID_PANEL_CONCURRENCY)
+        .try_collect()
+        .await?;
+    let (layout, size) = grid_layout(domains.len(), opts);
+    let mut buf = String::new();
+    {
+        let root_area = SVGBackend::with_string(&mut buf, size).into_drawing_area();
+        // Fill in any leftover space in the last row
+        root_area.fill(&opts.palette().background)?;
+        for ((area, domain), points) in root_area
+            .split_evenly(layout)
+            .iter()
+            .zip(domains)
+            .zip(panels)
+        {
+            draw_chart(area, points, domain, opts)?;
+        }
+    }
+    if let (Some(conn), Some(cache_key), Some(latest)) = (conn.as_mut(), cache_key, latest) {
+        if let Err(err) = conn
+            .set_ex::<_, _, ()>(&cache_key, &buf, cache_ttl(&latest))
+            .await
+        {
+            warn!("Unable to cache grid chart: {}", err);
+        }
+    }
+    Ok(buf)
+}
+
+/// Rows and columns for a grid of charts, keeping it roughly square,
+/// and the size of the whole grid. Unless given, the size grows with
+/// the number of panels.
+fn grid_layout(panels: usize, opts: &ChartOptions) -> ((usize, usize), (u32, u32)) {
+    let cols = (panels.max(1) as f64).sqrt().ceil() as usize;
+    let rows = panels.max(1).div_ceil(cols);
+    let size = (
+        opts.width
+            .unwrap_or(GRID_PANEL_SIZE.0 * cols as u32)
+            .clamp(200, 4000),
+        opts.height
+            .unwrap_or(GRID_PANEL_SIZE.1 * rows as u32)
+            .clamp(200, 4000),
+    );
+    ((rows, cols), size)
+}
+
+/// Title for a chart, mentioning the domain if there is one
+fn chart_caption(domain: Option<&str>, opts: &ChartOptions) -> String {
+    match (domain, opts.metric.unwrap_or(Metric::Total)) {
+        (Some(domain), Metric::Total) => format!("{} short URLs", domain),
+        (None, Metric::Total) => "w.wiki short URLs over time".to_string(),
+        (_, Metric::AvgPerDomain) => "w.wiki short URLs per domain over time".to_string(),
+    }
+}
+
+/// Redis key a rendered chart is cached under
+fn chart_cache_key(
+    domain: Option<&str>,
+    opts: &ChartOptions,
+    latest: &Path,
+    today: NaiveDate,
+) -> String {
+    let (width, height) = opts.size();
+    format!(
+        "shorturls:chart:{}:{}x{}:{:?}:{:?}:{:?}:{}:{}:{}:{:?}:{}:{}",
+        domain.unwrap_or("*"),
+        width,
+        height,
+        opts.scale.unwrap_or(Scale::Linear),
+        opts.interval.unwrap_or(Interval::Day),
+        opts.theme.unwrap_or(Theme::Light),
+        opts.color.as_deref().and_then(parse_color).map_or_else(
+            String::new,
+            |RGBColor(r, g, b)| format!("{:02x}{:02x}{:02x}", r, g, b)
+        ),
+        opts.overlay
+            .map_or_else(String::new, |overlay| format!("{:?}", overlay)),
+        // The chart changes at midnight when today's data file is left out
+        opts.cutoff(today)
+            .map_or_else(String::new, |cutoff| format!(
+                "notoday{}",
+                cutoff.format("%Y%m%d")
+            )),
+        opts.metric.unwrap_or(Metric::Total),
+        opts.since_date()
+            .map_or_else(String::new, |since| since.to_string()),
+        data_version(latest)
+    )
+}
+
+/// Generate a PNG chart
+async fn chart2_png(domain: Option<&str>, opts: &ChartOptions) -> Result<Vec<u8>> {
+    use plotters::prelude::*;
+    let points = chart_points(domain, opts).await?;
+    let (width, height) = opts.size();
+    let mut pixels = vec![0; (width * height * 3) as usize];
+    {
+        let root_area = BitMapBackend::with_buffer(&mut pixels, opts.size()).into_drawing_area();
+        draw_chart(&root_area, points, &chart_caption(domain, opts), opts)?;
+    }
+    let image = image::RgbImage::from_raw(width, height, pixels)
+        .ok_or_else(|| anyhow!("Unable to build image from chart"))?;
+    let mut png = Vec::new();
+    image.write_to(
+        &mut std::io::Cursor::new(&mut png),
+        image::ImageOutputFormat::Png,
+    )?;
+    Ok(png)
+}
+
+/// Maximum number of results returned by a search
+const SEARCH_LIMIT: usize = 100;
+
+/// Tera template for search results
+#[derive(Serialize)]
+struct SearchTemplate {
+    query: String,
+    mode: String,
+    results: Vec<DomainTemplate>,
+}
+
+/// How the search query is matched against domains
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum SearchMode {
+    Substring,
+    Prefix,
+    Regex,
+}
+
+/// Maximum compiled size of a search regex, so pathological
+/// patterns are rejected instead of eating memory
+const SEARCH_REGEX_SIZE_LIMIT: usize = 1 << 16;
+
+/// A search query, ready to be matched against domains
+enum Matcher {
+    Substring(String),
+    Prefix(String),
+    Regex(regex::Regex),
+}
+
+impl Matcher {
+    /// Compile a query, returning an error message if it's an invalid regex
+    fn new(query: &str, mode: SearchMode) -> Result<Matcher, String> {
+        Ok(match mode {
+            SearchMode::Substring => Matcher::Substring(query.to_lowercase()),
+            SearchMode::Prefix => Matcher::Prefix(query.to_lowercase()),
+            SearchMode::Regex => Matcher::Regex(
+                regex::RegexBuilder::new(query)
+                    .case_insensitive(true)
+                    .size_limit(SEARCH_REGEX_SIZE_LIMIT)
+                    .build()
+                    .map_err(|err| format!("Invalid regex: {}", err))?,
+            ),
+        })
+    }
+
+    fn is_match(&self, domain: &str) -> bool {
+        match self {
+            Matcher::Substring(needle) => domain.to_lowercase().contains(needle),
+            Matcher::Prefix(needle) => domain.to_lowercase().starts_with(needle),
+            Matcher::Regex(re) => re.is_match(domain),
+        }
+    }
+}
+
+#[get("/search?<q>&<mode>")]
+async fn search(
+    q: Option<String>,
+    mode: Option<SearchMode>,
+) -> Result<Template, (Status, Template)> {
+    build_search(q.unwrap_or_default(), mode.unwrap_or(SearchMode::Substring))
+        .await
+        .map(|results| Template::render("search", results))
+        .map_err(error_page)
+}
+
+#[get("/search.json?<q>&<mode>")]
+async fn search_api(
+    q: Option<String>,
+    mode: Option<SearchMode>,
+) -> Result<Json<SearchTemplate>, AppError> {
+    build_search(q.unwrap_or_default(), mode.unwrap_or(SearchMode::Substring))
+        .await
+        .map(Json)
+}
+
+/// Find domains matching the (case-insensitive) query in the latest data
+async fn build_search(query: String, mode: SearchMode) -> Result<SearchTemplate, AppError> {
+    let mode_name = format!("{:?}", mode).to_lowercase();
+    let needle = query.trim();
+    if needle.is_empty() {
+        return Ok(SearchTemplate {
+            query,
+            mode: mode_name,
+            results: vec![],
+        });
+    }
+    let matcher = Matcher::new(needle, mode).map_err(AppError::BadRequest)?;
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    // stats are already sorted by count, so results will be too
+    let results = data
+        .stats
+        .into_iter()
+        .filter(|dinfo| matcher.is_match(&dinfo.domain))
+        .take(SEARCH_LIMIT)
+        .collect();
+    Ok(SearchTemplate {
+        query,
+        mode: mode_name,
+        results,
+    })
+}
+
+/// A single point in the history of total short URLs
+#[derive(Serialize)]
+struct HistoryPoint {
+    date: String,
+    total: i32,
+}
+
+#[get("/history.json?<from>&<to>&<exclude_today>")]
+async fn history_api(
+    from: Option<&str>,
+    to: Option<&str>,
+    exclude_today: Option<bool>,
+    _limit: RateLimit,
+) -> Result<Json<Vec<HistoryPoint>>, AppError> {
+    let from = from.map(parse_query_date).transpose()?;
+    let to = to.map(parse_query_date).transpose()?;
+    build_history(from, to, exclude_today.unwrap_or(false))
+        .await
+        .map(Json)
+        .map_err(AppError::from)
+}
+
+/// Build the history of total short URLs, optionally limited
+/// to an inclusive date range
+async fn build_history(
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    exclude_today: bool,
+) -> Result<Vec<HistoryPoint>> {
+    let client = connect_redis()?;
+    let mut history = Vec::new();
+    for data in find_data_for_trend(exclude_today)? {
+        let date = parse_date(data.file_name().unwrap().to_str().unwrap())?;
+        if from.is_some_and(|from| date < from) || to.is_some_and(|to| date > to) {
+            continue;
+        }
+        let info = get_data(data, &client).await?;
+        history.push(HistoryPoint {
+            date: date.to_string(),
+            total: info.total,
+        });
+    }
+    Ok(history)
+}
+
+/// Increase in total short URLs between two consecutive data files
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Spike {
+    date: String,
+    /// Date of the data file before it
+    previous: String,
+    delta: i64,
+}
+
+/// Number of spikes to return by default
+const DEFAULT_SPIKES: usize = 10;
+
+#[get("/spikes.json?<limit>")]
+async fn spikes_api(
+    limit: Option<usize>,
+    _rate_limit: RateLimit,
+) -> Result<Json<Vec<Spike>>, AppError> {
+    let limit = limit.unwrap_or(DEFAULT_SPIKES);
+    if limit == 0 {
+        return Err(AppError::BadRequest(
+            "limit must be a positive number".to_string(),
+        ));
+    }
+    let mut spikes = build_spikes().await?;
+    spikes.truncate(limit);
+    Ok(Json(spikes))
+}
+
+/// Every day-over-day increase, biggest first. Building it means reading
+/// every data file, so it's cached in Redis until the next data file
+/// shows up.
+async fn build_spikes() -> Result<Vec<Spike>> {
+    let latest = get_latest_data()?;
+    let cache_key = format!("shorturls:spikes:{}", data_version(&latest));
+    let client = connect_redis()?;
+    let mut conn = redis_connection(&client).await.ok();
+    if let Some(conn) = conn.as_mut() {
+        let cached: Option<String> = conn.get(&cache_key).await?;
+        if let Some(spikes) = cached.and_then(|json| serde_json::from_str(&json).ok()) {
+            return Ok(spikes);
+        }
+    }
+    let spikes = find_spikes(&build_history(None, None, false).await?);
+    if let Some(conn) = conn.as_mut() {
+        conn.set_ex::<_, _, ()>(
+            &cache_key,
+            serde_json::to_string(&spikes)?,
+            cache_ttl(&latest),
+        )
+        .await?;
+    }
+    Ok(spikes)
+}
+
+/// Find the increases between consecutive points in the history,
+/// biggest first. Ties are broken by date, most recent first.
+fn find_spikes(history: &[HistoryPoint]) -> Vec<Spike> {
+    let mut spikes: Vec<Spike> = history
+        .windows(2)
+        .filter_map(|pair| {
+            let delta = pair[1].total as i64 - pair[0].total as i64;
+            (delta > 0).then(|| Spike {
+                date: pair[1].date.clone(),
+                previous: pair[0].date.clone(),
+                delta,
+            })
+        })
+        .collect();
+    spikes.sort_by(|a, b| b.delta.cmp(&a.delta).then_with(|| b.date.cmp(&a.date)));
+    spikes
+}
+
+/// Mean number of short URLs per domain in a single data file
+#[derive(Serialize)]
+struct AvgPerDomainPoint {
+    date: String,
+    total: i32,
+    domains: usize,
+    average: f32,
+}
+
+#[get("/avgperdomain.json?<exclude_today>")]
+async fn avg_per_domain_api(
+    exclude_today: Option<bool>,
+    _limit: RateLimit,
+) -> Result<Json<Vec<AvgPerDomainPoint>>, AppError> {
+    let series = gather_series(None, exclude_today.unwrap_or(false), None).await?;
+    Ok(Json(avg_per_domain_points(&series)))
+}
+
+/// Average for each data file, skipping any without domains
+fn avg_per_domain_points(series: &ChartSeries) -> Vec<AvgPerDomainPoint> {
+    series
+        .total
+        .iter()
+        .zip(&series.domains)
+        .filter(|(_, (_, domains))| *domains > 0)
+        .map(|((date, total), (_, domains))| {
+            let average = *total as f32 / *domains as f32;
+            AvgPerDomainPoint {
+                date: date.to_string(),
+                total: *total,
+                domains: *domains,
+                // Round to two decimal places
+                average: (average * 100.0).round() / 100.0,
+            }
+        })
+        .collect()
+}
+
+/// Short code lengths in a single data file
+#[derive(Serialize)]
+struct CodeLengthsPoint {
+    date: String,
+    /// Number of short codes of each length
+    lengths: BTreeMap<usize, i32>,
+}
+
+#[get("/codelengths.json")]
+async fn codelengths_api(_limit: RateLimit) -> Result<Json<Vec<CodeLengthsPoint>>, AppError> {
+    build_codelengths().await.map(Json).map_err(AppError::from)
+}
+
+/// Build the history of short code lengths. Data files from before
+/// code lengths were counted are left out.
+async fn build_codelengths() -> Result<Vec<CodeLengthsPoint>> {
+    let client = connect_redis()?;
+    let mut history = Vec::new();
+    for data in find_data()? {
+        let date = parse_date(data.file_name().unwrap().to_str().unwrap())?;
+        let info = get_data(data, &client).await?;
+        if !info.code_lengths.is_empty() {
+            history.push(CodeLengthsPoint {
+                date: date.to_string(),
+                lengths: info.code_lengths,
+            });
+        }
+    }
+    Ok(history)
+}
+
+/// A single point in the history of a domain's short URLs
+#[derive(Serialize)]
+struct DomainHistoryPoint {
+    date: String,
+    count: i32,
+}
+
+#[get("/<domain>/history.json?<fill>&<exclude_today>")]
+async fn domain_history_api(
+    domain: String,
+    fill: Option<i32>,
+    exclude_today: Option<bool>,
+    _limit: RateLimit,
+) -> Result<Json<Vec<DomainHistoryPoint>>, AppError> {
+    match build_domain_history(&domain, fill, exclude_today.unwrap_or(false)).await {
+        Ok(Some(history)) => Ok(Json(history)),
+        Ok(None) => Err(AppError::unknown_domain()),
+        Err(err) => Err(AppError::from(err)),
+    }
+}
+
+/// Build the history of a domain's short URLs. Dates on which the
+/// domain doesn't appear are skipped, unless a `fill` value is provided.
+/// Returns `None` if the domain doesn't appear in any data file.
+async fn build_domain_history(
+    domain: &str,
+    fill: Option<i32>,
+    exclude_today: bool,
+) -> Result<Option<Vec<DomainHistoryPoint>>> {
+    let client = connect_redis()?;
+    let mut history = Vec::new();
+    let mut found = false;
+    for data in find_data_for_trend(exclude_today)? {
+        let date = parse_date(data.file_name().unwrap().to_str().unwrap())?;
+        let info = get_data(data, &client).await?;
+        let count = info
+            .stats
+            .iter()
+            .find(|dinfo| dinfo.domain == domain)
+            .map(|dinfo| dinfo.count);
+        found |= count.is_some();
+        if let Some(count) = count.or(fill) {
+            history.push(DomainHistoryPoint {
+                date: date.to_string(),
+                count,
+            });
+        }
+    }
+    Ok(if found { Some(history) } else { None })
+}
+
+/// A domain's rank in a single data file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RankHistoryPoint {
+    date: String,
+    /// Not set if the domain isn't in the data file
+    rank: Option<usize>,
+}
+
+#[get("/<domain>/rank-history.json")]
+async fn domain_rank_history_api(
+    domain: String,
+    _limit: RateLimit,
+) -> Result<Json<Vec<RankHistoryPoint>>, AppError> {
+    match build_rank_history(&domain).await {
+        Ok(Some(history)) => Ok(Json(history)),
+        Ok(None) => Err(AppError::unknown_domain()),
+        Err(err) => Err(AppError::from(err)),
+    }
+}
+
+/// Build the history of a domain's rank, cached until the next data
+/// file since it has to go through all of them. Returns `None` if the
+/// domain doesn't appear in any data file.
+async fn build_rank_history(domain: &str) -> Result<Option<Vec<RankHistoryPoint>>> {
+    let latest = get_latest_data()?;
+    let cache_key = format!("shorturls:rankhistory:{}:{}", domain, data_version(&latest));
+    let client = connect_redis()?;
+    let mut conn = redis_connection(&client).await.ok();
+    if let Some(conn) = conn.as_mut() {
+        let cached: Option<String> = conn.get(&cache_key).await?;
+        if let Some(history) = cached.and_then(|json| serde_json::from_str(&json).ok()) {
+            return Ok(Some(history));
+        }
+    }
+    let mut ranks = Vec::new();
+    for data in find_data()? {
+        let date = parse_date(data.file_name().unwrap().to_str().unwrap())?;
+        let info = get_data(data, &client).await?;
+        // get_data fills in each domain's rank from its position in the
+        // count-sorted stats
+        let rank = info
+            .stats
+            .iter()
+            .find(|dinfo| dinfo.domain == domain)
+            .map(|dinfo| dinfo.rank);
+        ranks.push((date, rank));
+    }
+    let history = match rank_history(ranks) {
+        Some(history) => history,
+        None => return Ok(None),
+    };
+    if let Some(conn) = conn.as_mut() {
+        conn.set_ex::<_, _, ()>(
+            &cache_key,
+            serde_json::to_string(&history)?,
+            cache_ttl(&latest),
+        )
+        .await?;
+    }
+    Ok(Some(history))
+}
+
+/// Turn dated ranks into history points, or `None` if the
+/// domain was never ranked
+fn rank_history(ranks: Vec<(NaiveDate, Option<usize>)>) -> Option<Vec<RankHistoryPoint>> {
+    if ranks.iter().all(|(_, rank)| rank.is_none()) {
+        return None;
+    }
+    Some(
+        ranks
+            .into_iter()
+            .map(|(date, rank)| RankHistoryPoint {
+                date: date.to_string(),
+                rank,
+            })
+            .collect(),
+    )
+}
+
+/// Change in a domain's count between two data files
+#[derive(Serialize)]
+struct GrowthEntry {
+    domain: String,
+    old_count: i32,
+    new_count: i32,
+    delta: i32,
+    /// Not set for domains that are new
+    pct_change: Option<f32>,
+    direction: Direction,
+}
+
+/// Which way a domain's count went, so the change can be colored
+/// without recomputing it
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Up,
+    Down,
+    Same,
+    /// Only in the newer data file
+    New,
+    /// Only in the older data file
+    Gone,
+}
+
+/// Response for the growth endpoint
+#[derive(Serialize)]
+struct GrowthTemplate {
+    from: String,
+    to: String,
+    domains: Vec<GrowthEntry>,
+}
+
+#[get("/growth.json?<period>")]
+async fn growth_api(period: Option<i64>) -> Result<Json<GrowthTemplate>, AppError> {
+    build_growth(period.unwrap_or(30))
+        .await
+        .map(Json)
+        .map_err(AppError::from)
+}
+
+/// Compare the latest data file against the one closest to
+/// `period` days earlier
+async fn build_growth(period: i64) -> Result<GrowthTemplate> {
+    let mut dated = dated_data()?;
+    let (to, latest) = dated.pop().ok_or_else(|| anyhow!(NO_DATA))?;
+    let target = growth_target(to, period);
+    // If there's only one data file, compare it against itself
+    let (from, earlier) = dated
+        .into_iter()
+        .min_by_key(|(date, _)| (*date - target).num_days().abs())
+        .unwrap_or_else(|| (to, latest.clone()));
+    let client = connect_redis()?;
+    let old = get_data(earlier, &client).await?;
+    let new = get_data(latest, &client).await?;
+    Ok(GrowthTemplate {
+        from: from.to_string(),
+        to: to.to_string(),
+        domains: compare_counts(&old, &new),
+    })
+}
+
+/// The date `period` days before `to`. Periods too long to represent
+/// reach back to the earliest possible date, i.e. the first data file.
+fn growth_target(to: NaiveDate, period: i64) -> NaiveDate {
+    chrono::Duration::try_days(period.max(1))
+        .and_then(|period| to.checked_sub_signed(period))
+        .unwrap_or(NaiveDate::MIN)
+}
+
+/// Overall growth between two data files
+#[derive(Serialize, Debug, PartialEq)]
+struct GrowthRateTemplate {
+    from: String,
+    to: String,
+    from_total: i32,
+    to_total: i32,
+    growth: i64,
+    days: i64,
+    /// Average short URLs added per day, unless both are the same day
+    per_day: Option<f64>,
+}
+
+#[get("/growthrate.json?<from>&<to>")]
+async fn growth_rate_api(
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Json<GrowthRateTemplate>, AppError> {
+    let from = from.map(parse_query_date).transpose()?;
+    let to = to.map(parse_query_date).transpose()?;
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(AppError::BadRequest(format!("{} is after {}", from, to)));
+        }
+    }
+    build_growth_rate(from, to).await.map(Json)
+}
+
+/// Compare the first and last data files, optionally limited
+/// to an inclusive date range
+async fn build_growth_rate(
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<GrowthRateTemplate, AppError> {
+    let dated = dated_data()?;
+    if dated.is_empty() {
+        return Err(AppError::NoData);
+    }
+    let mut window = dated.into_iter().filter(|(date, _)| {
+        !(from.is_some_and(|from| *date < from) || to.is_some_and(|to| *date > to))
+    });
+    let first = window
+        .next()
+        .ok_or_else(|| AppError::NotFound("No data in the requested range".to_string()))?;
+    let last = window.next_back().unwrap_or_else(|| first.clone());
+    let client = connect_redis()?;
+    let old = get_data(first.1, &client).await?;
+    let new = get_data(last.1, &client).await?;
+    Ok(growth_rate((first.0, old.total), (last.0, new.total)))
+}
+
+/// Growth between the totals of two dates
+fn growth_rate(first: (NaiveDate, i32), last: (NaiveDate, i32)) -> GrowthRateTemplate {
+    let growth = i64::from(last.1) - i64::from(first.1);
+    let days = (last.0 - first.0).num_days();
+    GrowthRateTemplate {
+        from: first.0.to_string(),
+        to: last.0.to_string(),
+        from_total: first.1,
+        to_total: last.1,
+        growth,
+        days,
+        // Round to two decimal places
+        per_day: (days > 0).then(|| (growth as f64 / days as f64 * 100.0).round() / 100.0),
+    }
+}
+
+/// Join two sets of stats by domain, sorted by the change in count
+fn compare_counts(old: &IndexTemplate, new: &IndexTemplate) -> Vec<GrowthEntry> {
+    let old_counts: HashMap<&str, i32> = old
+        .stats
+        .iter()
+        .map(|dinfo| (dinfo.domain.as_str(), dinfo.count))
+        .collect();
+    let mut entries: Vec<GrowthEntry> = new
+        .stats
+        .iter()
+        .map(|dinfo| {
+            let old_count = old_counts.get(dinfo.domain.as_str()).copied();
+            growth_entry(&dinfo.domain, old_count, Some(dinfo.count))
+        })
+        .collect();
+    let new_domains: HashSet<&str> = new
+        .stats
+        .iter()
+        .map(|dinfo| dinfo.domain.as_str())
+        .collect();
+    // Domains that disappeared
+    for dinfo in &old.stats {
+        if !new_domains.contains(dinfo.domain.as_str()) {
+            entries.push(growth_entry(&dinfo.domain, Some(dinfo.count), None));
+        }
+    }
+    entries.sort_by(|a, b| b.delta.cmp(&a.delta).then_with(|| a.domain.cmp(&b.domain)));
+    entries
+}
+
+#[get("/compare?<from>&<to>")]
+async fn compare(from: Option<&str>, to: Option<&str>) -> Result<Template, (Status, Template)> {
+    match (from, to) {
+        (Some(from), Some(to)) => build_compare(from, to)
+            .await
+            .map(|comparison| Template::render("compare", comparison))
+            .map_err(error_page),
+        // Just show the form
+        _ => Ok(Template::render(
+            "compare",
+            GrowthTemplate {
+                from: from.unwrap_or_default().to_string(),
+                to: to.unwrap_or_default().to_string(),
+                domains: vec![],
+            },
+        )),
+    }
+}
+
+#[get("/compare.json?<from>&<to>")]
+async fn compare_api(
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Json<GrowthTemplate>, AppError> {
+    match (from, to) {
+        (Some(from), Some(to)) => build_compare(from, to).await.map(Json),
+        _ => Err(AppError::BadRequest(
+            "Both from and to dates are required".to_string(),
+        )),
+    }
+}
+
+/// Compare the data files from two dates given as YYYY-MM-DD
+async fn build_compare(from: &str, to: &str) -> Result<GrowthTemplate, AppError> {
+    let from = parse_query_date(from)?;
+    let to = parse_query_date(to)?;
+    let client = connect_redis()?;
+    let old = get_data(data_for_date(from)?, &client).await?;
+    let new = get_data(data_for_date(to)?, &client).await?;
+    Ok(GrowthTemplate {
+        from: from.to_string(),
+        to: to.to_string(),
+        domains: compare_counts(&old, &new),
+    })
+}
+
+/// Ranking of the short URLs created between two snapshots
+#[derive(Serialize)]
+struct RangeTemplate {
+    /// Dates of the snapshots actually used, after clamping
+    from: String,
+    to: String,
+    total: i32,
+    stats: Vec<DomainTemplate>,
+}
+
+#[get("/range?<from>&<to>")]
+async fn range(from: Option<&str>, to: Option<&str>) -> Result<Template, (Status, Template)> {
+    match (from, to) {
+        (Some(from), Some(to)) => build_range(from, to)
+            .await
+            .map(|ranking| Template::render("range", ranking))
+            .map_err(error_page),
+        // Just show the form
+        _ => Ok(Template::render(
+            "range",
+            RangeTemplate {
+                from: from.unwrap_or_default().to_string(),
+                to: to.unwrap_or_default().to_string(),
+                total: 0,
+                stats: vec![],
+            },
+        )),
+    }
+}
+
+#[get("/range.json?<from>&<to>")]
+async fn range_api(from: Option<&str>, to: Option<&str>) -> Result<Json<RangeTemplate>, AppError> {
+    match (from, to) {
+        (Some(from), Some(to)) => build_range(from, to).await.map(Json),
+        _ => Err(AppError::BadRequest(
+            "Both from and to dates are required".to_string(),
+        )),
+    }
+}
+
+/// Rank domains by how many short URLs were created between two dates
+/// given as YYYY-MM-DD, which are clamped to the available snapshots
+async fn build_range(from: &str, to: &str) -> Result<RangeTemplate, AppError> {
+    let from = parse_query_date(from)?;
+    let to = parse_query_date(to)?;
+    if from > to {
+        return Err(AppError::BadRequest(format!("{} is after {}", from, to)));
+    }
+    let dated = dated_data()?;
+    let (from, old) = snapshot_for(&dated, from).ok_or_else(|| AppError::NoData)?;
+    let (to, new) = snapshot_for(&dated, to).ok_or_else(|| AppError::NoData)?;
+    let client = connect_redis()?;
+    let old = get_data(old, &client).await?;
+    let new = get_data(new, &client).await?;
+    Ok(range_ranking(from, to, &old, &new))
+}
+
+/// The latest snapshot on or before a date, clamping dates outside the
+/// available range to the first or last snapshot
+fn snapshot_for(dated: &[(NaiveDate, PathBuf)], date: NaiveDate) -> Option<(NaiveDate, PathBuf)> {
+    dated
+        .iter()
+        .rev()
+        .find(|(found, _)| *found <= date)
+        .or_else(|| dated.first())
+        .cloned()
+}
+
+/// Turn the positive changes between two snapshots into a ranking
+fn range_ranking(
+    from: NaiveDate,
+    to: NaiveDate,
+    old: &IndexTemplate,
+    new: &IndexTemplate,
+) -> RangeTemplate {
+    let created: Vec<GrowthEntry> = compare_counts(old, new)
+        .into_iter()
+        .filter(|entry| entry.delta > 0)
+        .collect();
+    let total = created.iter().map(|entry| entry.delta).sum();
+    let stats = created
+        .into_iter()
+        .enumerate()
+        .map(|(idx, entry)| DomainTemplate {
+            count: entry.delta,
+            // Round to two decimal places
+            percentage: (entry.delta as f32 / total as f32 * 10000.0).round() / 100.0,
+            rank: idx + 1,
+            unicode: unicode_host(&entry.domain),
+            domain: entry.domain,
+        })
+        .collect();
+    RangeTemplate {
+        from: from.to_string(),
+        to: to.to_string(),
+        total,
+        stats,
+    }
+}
+
+fn growth_entry(domain: &str, old_count: Option<i32>, new_count: Option<i32>) -> GrowthEntry {
+    let direction = match (old_count, new_count) {
+        (None, Some(_)) => Direction::New,
+        (Some(_), None) => Direction::Gone,
+        _ => match new_count.cmp(&old_count) {
+            std::cmp::Ordering::Greater => Direction::Up,
+            std::cmp::Ordering::Less => Direction::Down,
+            std::cmp::Ordering::Equal => Direction::Same,
+        },
+    };
+    let old_count = old_count.unwrap_or(0);
+    let new_count = new_count.unwrap_or(0);
+    let delta = new_count - old_count;
+    GrowthEntry {
+        domain: domain.to_string(),
+        old_count,
+        new_count,
+        delta,
+        pct_change: if old_count > 0 {
+            // Round to two decimal places
+            Some((delta as f32 / old_count as f32 * 10000.0).round() / 100.0)
+        } else {
+            None
+        },
+        direction,
+    }
+}
+
+/// Short URLs aggregated by top-level domain
+#[derive(Serialize)]
+struct TldEntry {
+    tld: String,
+    count: i32,
+    /// Number of distinct domains under this TLD
+    domains: usize,
+}
+
+/// Short URLs aggregated by Wikimedia project family
+#[derive(Serialize)]
+struct ProjectEntry {
+    project: String,
+    count: i32,
+    /// Number of distinct domains in this project family
+    domains: usize,
+}
+
+/// Domains that are only in one of the two latest data files
+#[derive(Serialize)]
+struct DomainDiffTemplate {
+    /// Date of the previous data file
+    from: Option<String>,
+    /// Date of the latest data file
+    to: Option<String>,
+    domains: Vec<DomainTemplate>,
+    /// Why there's nothing to compare, if that's the case
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+#[get("/new.json")]
+async fn new_api() -> Result<Json<DomainDiffTemplate>, AppError> {
+    build_domain_diff("new", |old, new| new_domains(&old, new))
+        .await
+        .map(Json)
+        .map_err(AppError::from)
+}
+
+#[get("/removed.json")]
+async fn removed_api() -> Result<Json<DomainDiffTemplate>, AppError> {
+    build_domain_diff("removed", |old, new| removed_domains(old, &new))
+        .await
+        .map(Json)
+        .map_err(AppError::from)
+}
+
+/// Compare the latest data file against the one before it, with `diff`
+/// given the previous and latest data
+async fn build_domain_diff(
+    what: &str,
+    diff: impl FnOnce(IndexTemplate, IndexTemplate) -> Vec<DomainTemplate>,
+) -> Result<DomainDiffTemplate> {
+    let mut dated = dated_data()?;
+    let (latest, previous) = match (dated.pop(), dated.pop()) {
+        (Some(latest), Some(previous)) => (latest, previous),
+        (latest, _) => {
+            return Ok(DomainDiffTemplate {
+                from: None,
+                to: latest.map(|(date, _)| date.to_string()),
+                domains: vec![],
+                note: Some(format!(
+                    "At least two data files are needed to find {} domains",
+                    what
+                )),
+            })
+        }
+    };
+    let client = connect_redis()?;
+    let old = get_data(previous.1, &client).await?;
+    let new = get_data(latest.1, &client).await?;
+    Ok(DomainDiffTemplate {
+        from: Some(previous.0.to_string()),
+        to: Some(latest.0.to_string()),
+        domains: diff(old, new),
+        note: None,
+    })
+}
+
+/// Domains in `new` that aren't in `old`, keeping the order of `new`
+fn new_domains(old: &IndexTemplate, new: IndexTemplate) -> Vec<DomainTemplate> {
+    let old_domains: HashSet<&str> = old
+        .stats
+        .iter()
+        .map(|dinfo| dinfo.domain.as_str())
+        .collect();
+    new.stats
+        .into_iter()
+        .filter(|dinfo| !old_domains.contains(dinfo.domain.as_str()))
+        .collect()
+}
+
+/// Domains in `old` that are missing or down to zero in `new`, keeping
+/// the order and counts of `old`. Dumps are cumulative, so this means
+/// short URLs were deleted.
+fn removed_domains(old: IndexTemplate, new: &IndexTemplate) -> Vec<DomainTemplate> {
+    let new_domains: HashSet<&str> = new
+        .stats
+        .iter()
+        .filter(|dinfo| dinfo.count > 0)
+        .map(|dinfo| dinfo.domain.as_str())
+        .collect();
+    old.stats
+        .into_iter()
+        .filter(|dinfo| !new_domains.contains(dinfo.domain.as_str()))
+        .collect()
+}
+
+/// When a domain first showed up in the data
+#[derive(Serialize)]
+struct FirstSeenTemplate {
+    domain: String,
+    date: String,
+}
+
+// Ranked below the domain chart/history routes, which also match
+// two-segment paths
+#[get("/firstseen/<domain>", rank = 2)]
+async fn firstseen(domain: String) -> Result<Template, (Status, Template)> {
+    build_firstseen(domain)
+        .await
+        .map(|firstseen| Template::render("firstseen", firstseen))
+        .map_err(error_page)
+}
+
+#[get("/firstseen/<domain>/api.json")]
+async fn firstseen_api(domain: String) -> Result<Json<FirstSeenTemplate>, AppError> {
+    build_firstseen(domain).await.map(Json)
+}
+
+/// Look up when a domain first appeared
+async fn build_firstseen(domain: String) -> Result<FirstSeenTemplate, AppError> {
+    let dates = first_seen_dates().await?;
+    match dates.get(&domain) {
+        Some(date) => Ok(FirstSeenTemplate {
+            date: date.clone(),
+            domain,
+        }),
+        None => Err(AppError::unknown_domain()),
+    }
+}
+
+/// Map of every domain to the date of the first data file it has a
+/// nonzero count in. Building it means reading every data file, so
+/// it's cached in Redis until the next data file shows up.
+async fn first_seen_dates() -> Result<HashMap<String, String>> {
+    let dated = dated_data()?;
+    let latest = match dated.last() {
+        Some((_, latest)) => latest.clone(),
+        None => return Err(anyhow!(NO_DATA)),
+    };
+    let cache_key = format!("shorturls:firstseen:{}", data_version(&latest));
+    let client = connect_redis()?;
+    let mut conn = redis_connection(&client).await.ok();
+    if let Some(conn) = conn.as_mut() {
+        let cached: Option<String> = conn.get(&cache_key).await?;
+        if let Some(dates) = cached.and_then(|json| serde_json::from_str(&json).ok()) {
+            return Ok(dates);
+        }
+    }
+    let mut dates = HashMap::new();
+    for (date, path) in dated {
+        for dinfo in get_data(path, &client).await?.stats {
+            if dinfo.count > 0 {
+                dates
+                    .entry(dinfo.domain)
+                    .or_insert_with(|| date.to_string());
+            }
+        }
+    }
+    if let Some(conn) = conn.as_mut() {
+        conn.set_ex::<_, _, ()>(
+            &cache_key,
+            serde_json::to_string(&dates)?,
+            cache_ttl(&latest),
+        )
+        .await?;
+    }
+    Ok(dates)
+}
+
+/// Everything needed to render the main page in one response
+#[derive(Serialize)]
+struct DashboardTemplate {
+    index: IndexPage,
+    dates: DatesTemplate,
+    /// Total short URLs in each data file, as `[date, total]` pairs
+    history: Vec<(String, i64)>,
+}
+
+#[get("/dashboard.json?<opts..>")]
+async fn dashboard_api(
+    opts: IndexOptions,
+    _limit: RateLimit,
+) -> Result<Json<DashboardTemplate>, AppError> {
+    opts.validate().map_err(AppError::BadRequest)?;
+    build_dashboard(&opts)
+        .await
+        .map(Json)
+        .map_err(AppError::from)
+}
+
+async fn build_dashboard(opts: &IndexOptions) -> Result<DashboardTemplate> {
+    let index = build_index(opts).await?;
+    let dates = snapshot_dates(&find_data()?);
+    let series = gather_series(None, false, None).await?;
+    let history = series
+        .total
+        .into_iter()
+        .map(|(date, total)| (date.format("%Y-%m-%d").to_string(), i64::from(total)))
+        .collect();
+    Ok(DashboardTemplate {
+        index,
+        dates,
+        history,
+    })
+}
+
+/// Split of short URLs between Wikimedia sites and everything else
+#[derive(Serialize)]
+struct BreakdownTemplate {
+    total: i32,
+    wikimedia: i32,
+    external: i32,
+    /// Percentage of all short URLs that point to Wikimedia sites
+    wikimedia_share: f32,
+}
+
+#[get("/breakdown.json")]
+async fn breakdown_api() -> Result<Json<BreakdownTemplate>, AppError> {
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    Ok(Json(breakdown(&data)))
+}
+
+fn breakdown(data: &IndexTemplate) -> BreakdownTemplate {
+    let wikimedia_total = data.count_wikimedia();
+    BreakdownTemplate {
+        total: data.total,
+        wikimedia: wikimedia_total,
+        external: data.total - wikimedia_total,
+        wikimedia_share: if data.total > 0 {
+            // Round to two decimal places
+            (wikimedia_total as f32 / data.total as f32 * 10000.0).round() / 100.0
+        } else {
+            0.0
+        },
+    }
+}
+
+/// Dates of all the available snapshots
+#[derive(Serialize)]
+struct DatesTemplate {
+    /// Oldest first, as `YYYY-MM-DD`
+    dates: Vec<String>,
+    min: Option<String>,
+    max: Option<String>,
+}
+
+#[get("/dates.json")]
+fn dates_api() -> Result<Json<DatesTemplate>, AppError> {
+    let files = find_data()?;
+    Ok(Json(snapshot_dates(&files)))
+}
+
+/// Pull the dates out of data file names, ignoring any that don't parse
+fn snapshot_dates(files: &[PathBuf]) -> DatesTemplate {
+    let mut dates: Vec<NaiveDate> = files
+        .iter()
+        .filter_map(|path| parse_date(path.file_name()?.to_str()?).ok())
+        .collect();
+    dates.sort();
+    let format = |date: &NaiveDate| date.format("%Y-%m-%d").to_string();
+    DatesTemplate {
+        min: dates.first().map(format),
+        max: dates.last().map(format),
+        dates: dates.iter().map(format).collect(),
+    }
+}
+
+/// Statistics about how short URLs are distributed across domains
+#[derive(Serialize)]
+struct SummaryTemplate {
+    total: i32,
+    distinct_domains: usize,
+    /// Average number of short URLs per domain
+    mean: f32,
+    /// Count of the domain at the 50th, 90th and 99th percentiles
+    p50: i32,
+    p90: i32,
+    p99: i32,
+    /// Percentage of all short URLs that point to the top 10 domains
+    top10_share: f32,
+}
+
+#[get("/summary.json")]
+async fn summary_api() -> Result<Json<SummaryTemplate>, AppError> {
+    build_summary().await.map(Json).map_err(AppError::from)
+}
+
+/// How concentrated short URLs are in a few domains
+#[derive(Serialize)]
+struct ConcentrationTemplate {
+    date: String,
+    total: i32,
+    distinct_domains: usize,
+    /// Gini coefficient of the counts, from 0 (every domain has the same
+    /// count) to almost 1 (a single domain has all of them)
+    gini: f64,
+    /// Number of domains in the top 1%, at least one
+    top1pct_domains: usize,
+    /// Percentage of all short URLs that point to the top 1% of domains
+    top1pct_share: f32,
+}
+
+#[get("/concentration.json")]
+async fn concentration_api() -> Result<Json<ConcentrationTemplate>, AppError> {
+    let latest = get_latest_data()?;
+    let date = parse_date(latest.file_name().unwrap().to_str().unwrap())?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    Ok(Json(concentration(date, &data)))
+}
+
+/// Compute how concentrated the counts are, relying on `stats` being
+/// sorted by count (descending)
+fn concentration(date: NaiveDate, data: &IndexTemplate) -> ConcentrationTemplate {
+    let domains = data.stats.len();
+    let top1pct_domains = domains.div_ceil(100);
+    let top1pct: i32 = data
+        .stats
+        .iter()
+        .take(top1pct_domains)
+        .map(|dinfo| dinfo.count)
+        .sum();
+    ConcentrationTemplate {
+        date: date.to_string(),
+        total: data.total,
+        distinct_domains: domains,
+        // Round to four decimal places
+        gini: (gini(&data.stats) * 10_000.0).round() / 10_000.0,
+        top1pct_domains,
+        top1pct_share: if data.total > 0 {
+            (top1pct as f32 / data.total as f32 * 10_000.0).round() / 100.0
+        } else {
+            0.0
+        },
+    }
+}
+
+/// Gini coefficient of counts sorted in descending order, from the
+/// area under the Lorenz curve
+fn gini(stats: &[DomainTemplate]) -> f64 {
+    let n = stats.len() as f64;
+    let (sum, weighted) =
+        stats
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(sum, weighted), (idx, dinfo)| {
+                // Position counting up from the smallest domain
+                let pos = n - idx as f64;
+                let count = f64::from(dinfo.count);
+                (sum + count, weighted + pos * count)
+            });
+    if sum <= 0.0 {
+        return 0.0;
+    }
+    2.0 * weighted / (n * sum) - (n + 1.0) / n
+}
+
+/// How to order the domain list
+#[derive(FromFormField, Clone, Copy, Debug, PartialEq)]
+enum DomainSort {
+    /// Most short URLs first
+    Count,
+    /// Alphabetical, the default
+    Alpha,
+}
+
+#[get("/domains.json?<sort>")]
+async fn domains_api(sort: Option<DomainSort>) -> Result<Json<Vec<String>>, AppError> {
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    Ok(Json(domain_names(data, sort.unwrap_or(DomainSort::Alpha))))
+}
+
+/// Just the domain names, relying on `stats` being sorted by count
+/// (descending)
+fn domain_names(data: IndexTemplate, sort: DomainSort) -> Vec<String> {
+    let mut domains: Vec<String> = data.stats.into_iter().map(|dinfo| dinfo.domain).collect();
+    if sort == DomainSort::Alpha {
+        domains.sort();
+    }
+    domains
+}
+
+/// Number of completions returned by default
+const DEFAULT_COMPLETIONS: usize = 10;
+
+/// Most completions that can be requested at once
+const MAX_COMPLETIONS: usize = 100;
+
+/// Domains starting with `q`, most short URLs first. Meant for typeahead,
+/// so problems result in no completions rather than an error.
+#[get("/autocomplete?<q>&<limit>")]
+async fn autocomplete(q: Option<&str>, limit: Option<usize>) -> Json<Vec<String>> {
+    let prefix = q.unwrap_or_default().trim().to_lowercase();
+    if prefix.is_empty() {
+        return Json(vec![]);
+    }
+    let limit = limit
+        .unwrap_or(DEFAULT_COMPLETIONS)
+        .clamp(1, MAX_COMPLETIONS);
+    match completion_list().await {
+        Ok(domains) => Json(complete(&domains, &prefix, limit)),
+        Err(err) => {
+            error!("Unable to autocomplete: {:#}", err);
+            Json(vec![])
+        }
+    }
+}
+
+/// Completion list for a single data file, kept in memory so typing
+/// doesn't fetch and parse the whole list on every keystroke
+struct CompletionCache {
+    latest: Mutex<Option<(String, Arc<Vec<String>>)>>,
+}
+
+impl CompletionCache {
+    const fn new() -> Self {
+        Self {
+            latest: Mutex::new(None),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<(String, Arc<Vec<String>>)>> {
+        self.latest.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The list for the given version of a data file (see [`data_version`]),
+    /// if it's the one that's cached
+    fn get(&self, data: &str) -> Option<Arc<Vec<String>>> {
+        match &*self.lock() {
+            Some((version, domains)) if version == data => Some(domains.clone()),
+            _ => None,
+        }
+    }
+
+    /// Replace the cached list, e.g. once there's a new or re-extracted data file
+    fn set(&self, data: String, domains: Arc<Vec<String>>) {
+        *self.lock() = Some((data, domains));
+    }
+}
+
+static COMPLETIONS: CompletionCache = CompletionCache::new();
+
+/// All the domains in the latest data file by count
+async fn completion_list() -> Result<Arc<Vec<String>>> {
+    let latest = get_latest_data()?;
+    let version = data_version(&latest);
+    if let Some(domains) = COMPLETIONS.get(&version) {
+        return Ok(domains);
+    }
+    let domains = Arc::new(load_completion_list(&latest).await?);
+    COMPLETIONS.set(version, domains.clone());
+    Ok(domains)
+}
+
+/// Build the completion list for a data file, cached in Redis so other
+/// processes don't need to load the whole data file
+async fn load_completion_list(latest: &Path) -> Result<Vec<String>> {
+    let cache_key = format!("shorturls:autocomplete:{}", data_version(latest));
+    let client = connect_redis()?;
+    let mut conn = redis_connection(&client).await.ok();
+    if let Some(conn) = conn.as_mut() {
+        let cached: Option<String> = conn.get(&cache_key).await?;
+        if let Some(domains) = cached.and_then(|json| serde_json::from_str(&json).ok()) {
+            return Ok(domains);
+        }
+    }
+    let domains = domain_names(
+        get_data(latest.to_path_buf(), &client).await?,
+        DomainSort::Count,
+    );
+    if let Some(conn) = conn.as_mut() {
+        conn.set_ex::<_, _, ()>(
+            &cache_key,
+            serde_json::to_string(&domains)?,
+            cache_ttl(latest),
+        )
+        .await?;
+    }
+    Ok(domains)
+}
+
+/// The first `limit` domains starting with `prefix`, keeping their order
+fn complete(domains: &[String], prefix: &str, limit: usize) -> Vec<String> {
+    domains
+        .iter()
+        .filter(|domain| domain.starts_with(prefix))
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+/// Summarize the latest data file
+async fn build_summary() -> Result<SummaryTemplate> {
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    Ok(summarize(&get_data(latest, &client).await?))
+}
+
+/// Compute distribution statistics, relying on `stats` being sorted
+/// by count (descending)
+fn summarize(data: &IndexTemplate) -> SummaryTemplate {
+    let domains = data.stats.len();
+    let top10: i32 = data.stats.iter().take(10).map(|dinfo| dinfo.count).sum();
+    let round = |val: f32| (val * 100.0).round() / 100.0;
+    SummaryTemplate {
+        total: data.total,
+        distinct_domains: domains,
+        mean: if domains > 0 {
+            round(data.total as f32 / domains as f32)
+        } else {
+            0.0
+        },
+        p50: percentile(&data.stats, 50),
+        p90: percentile(&data.stats, 90),
+        p99: percentile(&data.stats, 99),
+        top10_share: if data.total > 0 {
+            round(top10 as f32 / data.total as f32 * 100.0)
+        } else {
+            0.0
+        },
+    }
+}
+
+/// Count at the given percentile (nearest-rank), where higher percentiles
+/// are bigger domains
+fn percentile(stats: &[DomainTemplate], pct: usize) -> i32 {
+    if stats.is_empty() {
+        return 0;
+    }
+    // Rank counting up from the smallest domain
+    let rank = (pct * stats.len()).div_ceil(100).max(1);
+    stats[stats.len() - rank].count
+}
+
+#[get("/tld.json")]
+async fn tld_api() -> Result<Json<Vec<TldEntry>>, AppError> {
+    build_tlds().await.map(Json).map_err(AppError::from)
+}
+
+#[get("/projects.json")]
+async fn projects_api() -> Result<Json<Vec<ProjectEntry>>, AppError> {
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    Ok(Json(aggregate_projects(&data)))
+}
+
+/// Aggregate data by project family, see `project_family`
+fn aggregate_projects(data: &IndexTemplate) -> Vec<ProjectEntry> {
+    let mut projects: HashMap<&str, (i32, usize)> = HashMap::new();
+    for dinfo in &data.stats {
+        let entry = projects
+            .entry(project_family(&dinfo.domain))
+            .or_insert((0, 0));
+        entry.0 += dinfo.count;
+        entry.1 += 1;
+    }
+    let mut entries: Vec<ProjectEntry> = projects
+        .into_iter()
+        .map(|(project, (count, domains))| ProjectEntry {
+            project: project.to_string(),
+            count,
+            domains,
+        })
+        .collect();
+    entries.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.project.cmp(&b.project))
+    });
+    entries
+}
+
+/// A domain requested from `/multi.json`
+#[derive(Serialize)]
+struct MultiEntry {
+    #[serde(flatten)]
+    domain: DomainTemplate,
+    /// Whether the domain is in the latest data, if not the count is 0
+    found: bool,
+}
+
+/// Most domains that can be requested from `/multi.json` at once
+const MAX_MULTI_DOMAINS: usize = 100;
+
+#[get("/multi.json?<domains>")]
+async fn multi_api(domains: Option<&str>) -> Result<Json<Vec<MultiEntry>>, AppError> {
+    let names = parse_domain_list(domains, MAX_MULTI_DOMAINS)?;
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    Ok(Json(pick_domains(&data, &names)))
+}
+
+/// Parse a comma-separated `?domains=` list, dropping blanks and repeats.
+/// Empty lists and ones with more than `max` domains are rejected.
+fn parse_domain_list(list: Option<&str>, max: usize) -> Result<Vec<&str>, AppError> {
+    let mut seen = HashSet::new();
+    let names: Vec<&str> = list
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty() && seen.insert(*name))
+        .collect();
+    if names.is_empty() {
+        return Err(AppError::BadRequest(
+            "domains must be a comma-separated list of domains".to_string(),
+        ));
+    }
+    if names.len() > max {
+        return Err(AppError::BadRequest(format!(
+            "At most {} domains can be requested at once",
+            max
+        )));
+    }
+    Ok(names)
+}
+
+/// Pick out the requested domains, in the order they were requested
+fn pick_domains(data: &IndexTemplate, names: &[&str]) -> Vec<MultiEntry> {
+    let stats: HashMap<&str, &DomainTemplate> = data
+        .stats
+        .iter()
+        .map(|dinfo| (dinfo.domain.as_str(), dinfo))
+        .collect();
+    names
+        .iter()
+        .map(|name| match stats.get(name) {
+            Some(dinfo) => MultiEntry {
+                domain: (*dinfo).clone(),
+                found: true,
+            },
+            None => MultiEntry {
+                domain: DomainTemplate {
+                    domain: name.to_string(),
+                    count: 0,
+                    percentage: 0.0,
+                    rank: 0,
+                    unicode: unicode_host(name),
+                },
+                found: false,
+            },
+        })
+        .collect()
+}
+
+/// Aggregate the latest data by TLD. IP addresses and hosts
+/// without a dot are bucketed into "other".
+async fn build_tlds() -> Result<Vec<TldEntry>> {
+    let latest = get_latest_data()?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    let mut tlds: HashMap<&str, (i32, usize)> = HashMap::new();
+    for dinfo in &data.stats {
+        let entry = tlds
+            .entry(public_suffix(&dinfo.domain).unwrap_or("other"))
+            .or_insert((0, 0));
+        entry.0 += dinfo.count;
+        entry.1 += 1;
+    }
+    let mut entries: Vec<TldEntry> = tlds
+        .into_iter()
+        .map(|(tld, (count, domains))| TldEntry {
+            tld: tld.to_string(),
+            count,
+            domains,
+        })
+        .collect();
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tld.cmp(&b.tld)));
+    Ok(entries)
+}
+
+/// Public URL of the tool, used for links in feeds
+const BASE_URL: &str = "https://shorturls.toolforge.org/";
+
+/// Maximum number of entries in the Atom feed
+const FEED_ENTRIES: usize = 30;
+
+#[get("/openapi.json")]
+fn openapi() -> Json<serde_json::Value> {
+    Json(openapi_spec())
+}
+
+/// JSON schema reference to one of the components below
+fn schema_ref(name: &str) -> serde_json::Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// JSON response with the given schema
+fn json_response(description: &str, schema: serde_json::Value) -> serde_json::Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } }
+    })
+}
+
+/// Optional query parameter
+fn query_param(name: &str, description: &str, schema: serde_json::Value) -> serde_json::Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": schema
+    })
+}
+
+/// OpenAPI 3.0 description of the JSON endpoints. The schemas are
+/// maintained by hand, so update them whenever the serde structs change.
+fn openapi_spec() -> serde_json::Value {
+    let domain_param = json!({
+        "name": "domain",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" }
+    });
+    let error = json_response("Error", schema_ref("ErrorTemplate"));
+    let rate_limited = json!({ "description": "Too many requests from this client" });
+    let exclude_today = query_param(
+        "exclude_today",
+        "Leave out today's dump, which may be incomplete",
+        json!({ "type": "boolean", "default": false }),
+    );
+    let date = json!({ "type": "string", "format": "date" });
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "w.wiki statistics",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "servers": [{ "url": BASE_URL }],
+        "paths": {
+            "/api.json": {
+                "get": {
+                    "summary": "Domains in the latest dump",
+                    "parameters": [
+                        query_param("group", "Merge subdomains into their registrable domain",
+                                    json!({ "type": "string", "enum": ["domain", "parent"] })),
+                        query_param("limit", "Only include the top N domains",
+                                    json!({ "type": "integer", "minimum": 1 })),
+                        query_param("min", "Hide domains with fewer short URLs, 1 (show everything) by default",
+                                    json!({ "type": "integer", "minimum": 1 })),
+                        query_param("sort", "Field to sort by",
+                                    json!({ "type": "string", "enum": ["count", "domain"] })),
+                        query_param("order", "Sort direction",
+                                    json!({ "type": "string", "enum": ["asc", "desc"] }))
+                    ],
+                    "responses": {
+                        "200": json_response("Index", schema_ref("IndexTemplate")),
+                        "400": error,
+                        "500": error
+                    }
+                }
+            },
+            "/date/{date}/api.json": {
+                "get": {
+                    "summary": "Domains in the dump from a specific date",
+                    "parameters": [
+                        {
+                            "name": "date",
+                            "in": "path",
+                            "required": true,
+                            "description": "Date of the dump (YYYYMMDD)",
+                            "schema": { "type": "string" }
+                        },
+                        query_param("group", "Merge subdomains into their registrable domain",
+                                    json!({ "type": "string", "enum": ["domain", "parent"] })),
+                        query_param("limit", "Only include the top N domains",
+                                    json!({ "type": "integer", "minimum": 1 })),
+                        query_param("min", "Hide domains with fewer short URLs, 1 (show everything) by default",
+                                    json!({ "type": "integer", "minimum": 1 })),
+                        query_param("sort", "Field to sort by",
+                                    json!({ "type": "string", "enum": ["count", "domain"] })),
+                        query_param("order", "Sort direction",
+                                    json!({ "type": "string", "enum": ["asc", "desc"] }))
+                    ],
+                    "responses": {
+                        "200": json_response("Index", schema_ref("IndexTemplate")),
+                        "400": error,
+                        "404": error,
+                        "500": error
+                    }
+                }
+            },
+            "/raw/{date}.data": {
+                "get": {
+                    "summary": "Unprocessed data file from a specific date, as a download",
+                    "parameters": [
+                        {
+                            "name": "date",
+                            "in": "path",
+                            "required": true,
+                            "description": "Date of the dump (YYYYMMDD)",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": json_response("Data file", schema_ref("IndexTemplate")),
+                        "400": error,
+                        "404": error,
+                        "500": error
+                    }
+                }
+            },
+            "/multi.json": {
+                "get": {
+                    "summary": "Several domains in the latest dump",
+                    "parameters": [
+                        {
+                            "name": "domains",
+                            "in": "query",
+                            "required": true,
+                            "description": "Comma-separated list of domains",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": json_response("Domains", json!({
+                            "type": "array",
+                            "items": schema_ref("MultiEntry")
+                        })),
+                        "400": error,
+                        "500": error
+                    }
+                }
+            },
+            "/{domain}/api.json": {
+                "get": {
+                    "summary": "A single domain in the latest dump",
+                    "parameters": [domain_param],
+                    "responses": {
+                        "200": json_response("Domain", schema_ref("DomainTemplate")),
+                        "404": error,
+                        "500": error
+                    }
+                }
+            },
+            "/{domain}/history.json": {
+                "get": {
+                    "summary": "Count for a domain in every dump",
+                    "parameters": [
+                        domain_param,
+                        query_param("fill", "Value to use for dumps the domain is missing from",
+                                    json!({ "type": "integer" })),
+                        exclude_today
+                    ],
+                    "responses": {
+                        "200": json_response("History", json!({
+                            "type": "array",
+                            "items": schema_ref("DomainHistoryPoint")
+                        })),
+                        "404": error,
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/{domain}/rank-history.json": {
+                "get": {
+                    "summary": "Rank of a domain in every dump",
+                    "parameters": [domain_param],
+                    "responses": {
+                        "200": json_response("Rank history", json!({
+                            "type": "array",
+                            "items": schema_ref("RankHistoryPoint")
+                        })),
+                        "404": error,
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/chart.json": {
+                "get": {
+                    "summary": "Total count in every dump, as drawn on the chart",
+                    "parameters": [
+                        query_param("interval", "Only keep the last point in each period",
+                                    json!({ "type": "string", "enum": ["day", "week", "month"] })),
+                        query_param("since", "First date to include (YYYY-MM-DD)", date.clone()),
+                        exclude_today
+                    ],
+                    "responses": {
+                        "200": json_response("Series", schema_ref("ChartSeriesTemplate")),
+                        "400": error,
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/{domain}/chart.json": {
+                "get": {
+                    "summary": "Total and domain counts in every dump, as drawn on the chart",
+                    "parameters": [
+                        domain_param,
+                        query_param("interval", "Only keep the last point in each period",
+                                    json!({ "type": "string", "enum": ["day", "week", "month"] })),
+                        query_param("since", "First date to include (YYYY-MM-DD)", date.clone()),
+                        exclude_today
+                    ],
+                    "responses": {
+                        "200": json_response("Series", schema_ref("ChartSeriesTemplate")),
+                        "400": error,
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/codelengths.json": {
+                "get": {
+                    "summary": "Number of short codes of each length in every dump",
+                    "responses": {
+                        "200": json_response("History", json!({
+                            "type": "array",
+                            "items": schema_ref("CodeLengthsPoint")
+                        })),
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/search.json": {
+                "get": {
+                    "summary": "Domains matching a query",
+                    "parameters": [
+                        query_param("q", "Query to search for", json!({ "type": "string" })),
+                        query_param("mode", "How to match the query, substring by default",
+                                    json!({ "type": "string", "enum": ["substring", "prefix", "regex"] }))
+                    ],
+                    "responses": {
+                        "200": json_response("Search results", schema_ref("SearchTemplate")),
+                        "400": error,
+                        "500": error
+                    }
+                }
+            },
+            "/history.json": {
+                "get": {
+                    "summary": "Total count in every dump",
+                    "parameters": [
+                        query_param("from", "First date to include (YYYY-MM-DD)", date.clone()),
+                        query_param("to", "Last date to include (YYYY-MM-DD)", date.clone()),
+                        exclude_today
+                    ],
+                    "responses": {
+                        "200": json_response("History", json!({
+                            "type": "array",
+                            "items": schema_ref("HistoryPoint")
+                        })),
+                        "400": error,
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/avgperdomain.json": {
+                "get": {
+                    "summary": "Mean number of short URLs per domain in every dump",
+                    "parameters": [exclude_today],
+                    "responses": {
+                        "200": json_response("Averages", json!({
+                            "type": "array",
+                            "items": schema_ref("AvgPerDomainPoint")
+                        })),
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/spikes.json": {
+                "get": {
+                    "summary": "Biggest day-over-day increases in the total count",
+                    "parameters": [
+                        query_param("limit", "Number of days to return, 10 by default",
+                                    json!({ "type": "integer", "minimum": 1 }))
+                    ],
+                    "responses": {
+                        "200": json_response("Spikes", json!({
+                            "type": "array",
+                            "items": schema_ref("Spike")
+                        })),
+                        "400": error,
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/growth.json": {
+                "get": {
+                    "summary": "Change in counts per domain over a period",
+                    "parameters": [
+                        query_param("period", "Number of days to compare over, 30 by default",
+                                    json!({ "type": "integer" }))
+                    ],
+                    "responses": {
+                        "200": json_response("Growth", schema_ref("GrowthTemplate")),
+                        "500": error
+                    }
+                }
+            },
+            "/growthrate.json": {
+                "get": {
+                    "summary": "Overall growth between the first and latest dumps",
+                    "parameters": [
+                        query_param("from", "First date to include (YYYY-MM-DD)", date.clone()),
+                        query_param("to", "Last date to include (YYYY-MM-DD)", date.clone())
+                    ],
+                    "responses": {
+                        "200": json_response("Growth rate", schema_ref("GrowthRateTemplate")),
+                        "400": error,
+                        "404": error,
+                        "500": error
+                    }
+                }
+            },
+            "/compare.json": {
+                "get": {
+                    "summary": "Change in counts per domain between two dumps",
+                    "parameters": [
+                        {
+                            "name": "from",
+                            "in": "query",
+                            "required": true,
+                            "description": "Date of the older dump (YYYY-MM-DD)",
+                            "schema": date.clone()
+                        },
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "required": true,
+                            "description": "Date of the newer dump (YYYY-MM-DD)",
+                            "schema": date.clone()
+                        }
+                    ],
+                    "responses": {
+                        "200": json_response("Comparison", schema_ref("GrowthTemplate")),
+                        "400": error,
+                        "404": error,
+                        "500": error
+                    }
+                }
+            },
+            "/range.json": {
+                "get": {
+                    "summary": "Domains ranked by short URLs created between two dates",
+                    "parameters": [
+                        {
+                            "name": "from",
+                            "in": "query",
+                            "required": true,
+                            "description": "Start date (YYYY-MM-DD), clamped to the available dumps",
+                            "schema": date.clone()
+                        },
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "required": true,
+                            "description": "End date (YYYY-MM-DD), clamped to the available dumps",
+                            "schema": date.clone()
+                        }
+                    ],
+                    "responses": {
+                        "200": json_response("Ranking", schema_ref("RangeTemplate")),
+                        "400": error,
+                        "500": error
+                    }
+                }
+            },
+            "/new.json": {
+                "get": {
+                    "summary": "Domains that weren't in the previous dump",
+                    "responses": {
+                        "200": json_response("New domains", schema_ref("DomainDiffTemplate")),
+                        "500": error
+                    }
+                }
+            },
+            "/removed.json": {
+                "get": {
+                    "summary": "Domains from the previous dump that are gone from the latest one",
+                    "responses": {
+                        "200": json_response("Removed domains", schema_ref("DomainDiffTemplate")),
+                        "500": error
+                    }
+                }
+            },
+            "/firstseen/{domain}/api.json": {
+                "get": {
+                    "summary": "Date of the first dump a domain appeared in",
+                    "parameters": [domain_param],
+                    "responses": {
+                        "200": json_response("First seen", schema_ref("FirstSeenTemplate")),
+                        "404": error,
+                        "500": error
+                    }
+                }
+            },
+            "/dashboard.json": {
+                "get": {
+                    "summary": "Latest dump, available dates and total history in one response",
+                    "parameters": [
+                        query_param("group", "How to group domains, not at all by default",
+                                    json!({ "type": "string", "enum": ["domain", "parent"] })),
+                        query_param("limit", "Only include the top N domains",
+                                    json!({ "type": "integer", "minimum": 1 })),
+                        query_param("min", "Hide domains with fewer short URLs, 1 (show everything) by default",
+                                    json!({ "type": "integer", "minimum": 1 })),
+                        query_param("sort", "Field to sort by",
+                                    json!({ "type": "string", "enum": ["count", "domain"] })),
+                        query_param("order", "Sort direction",
+                                    json!({ "type": "string", "enum": ["asc", "desc"] }))
+                    ],
+                    "responses": {
+                        "200": json_response("Dashboard", schema_ref("DashboardTemplate")),
+                        "400": error,
+                        "429": rate_limited,
+                        "500": error
+                    }
+                }
+            },
+            "/breakdown.json": {
+                "get": {
+                    "summary": "Short URLs to Wikimedia sites versus external sites",
+                    "responses": {
+                        "200": json_response("Breakdown", schema_ref("BreakdownTemplate")),
+                        "500": error
+                    }
+                }
+            },
+            "/dates.json": {
+                "get": {
+                    "summary": "Dates of all the available snapshots",
+                    "responses": {
+                        "200": json_response("Snapshot dates", schema_ref("DatesTemplate")),
+                        "500": error
+                    }
+                }
+            },
+            "/domains.json": {
+                "get": {
+                    "summary": "Names of all the domains in the latest dump",
+                    "parameters": [
+                        query_param("sort", "How to order the domains, alphabetical by default",
+                                    json!({ "type": "string", "enum": ["count", "alpha"] }))
+                    ],
+                    "responses": {
+                        "200": json_response("Domain names",
+                                             json!({ "type": "array", "items": { "type": "string" } })),
+                        "500": error
+                    }
+                }
+            },
+            "/autocomplete": {
+                "get": {
+                    "summary": "Domains in the latest dump starting with a prefix, most short URLs first",
+                    "parameters": [
+                        query_param("q", "Prefix to complete", json!({ "type": "string" })),
+                        query_param("limit", "Number of completions, 10 by default",
+                                    json!({ "type": "integer", "minimum": 1, "maximum": MAX_COMPLETIONS }))
+                    ],
+                    "responses": {
+                        "200": json_response("Domain names",
+                                             json!({ "type": "array", "items": { "type": "string" } }))
+                    }
+                }
+            },
+            "/summary.json": {
+                "get": {
+                    "summary": "Distribution of short URLs across domains in the latest dump",
+                    "responses": {
+                        "200": json_response("Summary", schema_ref("SummaryTemplate")),
+                        "500": error
+                    }
+                }
+            },
+            "/concentration.json": {
+                "get": {
+                    "summary": "How concentrated short URLs are in a few domains in the latest dump",
+                    "responses": {
+                        "200": json_response("Concentration", schema_ref("ConcentrationTemplate")),
+                        "500": error
+                    }
+                }
+            },
+            "/tld.json": {
+                "get": {
+                    "summary": "Counts aggregated by public suffix",
+                    "responses": {
+                        "200": json_response("TLDs", json!({
+                            "type": "array",
+                            "items": schema_ref("TldEntry")
+                        })),
+                        "500": error
+                    }
+                }
+            },
+            "/projects.json": {
+                "get": {
+                    "summary": "Counts aggregated by Wikimedia project family",
+                    "responses": {
+                        "200": json_response("Projects", json!({
+                            "type": "array",
+                            "items": schema_ref("ProjectEntry")
+                        })),
+                        "500": error
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "IndexTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "schema_version": { "type": "integer" },
+                        "stats": { "type": "array", "items": schema_ref("DomainTemplate") },
+                        "total": { "type": "integer" },
+                        "distinct_domains": { "type": "integer" },
+                        "schemes": {
+                            "type": "object",
+                            "additionalProperties": { "type": "integer" }
+                        },
+                        "skipped_unparseable": { "type": "integer" },
+                        "skipped_nohost": { "type": "integer" },
+                        "skipped_malformed": { "type": "integer" },
+                        "code_lengths": {
+                            "type": "object",
+                            "description": "Number of short codes of each length",
+                            "additionalProperties": { "type": "integer" }
+                        },
+                        "wikimedia_total": { "type": "integer" },
+                        "generated_at": {
+                            "type": "string",
+                            "format": "date-time",
+                            "nullable": true
+                        },
+                        "source_dump": { "type": "string", "nullable": true }
+                    }
+                },
+                "DomainTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "domain": { "type": "string" },
+                        "count": { "type": "integer" },
+                        "percentage": { "type": "number" },
+                        "rank": { "type": "integer" },
+                        "unicode": {
+                            "type": "string",
+                            "description": "Unicode form of an internationalized domain"
+                        }
+                    }
+                },
+                "MultiEntry": {
+                    "type": "object",
+                    "properties": {
+                        "domain": { "type": "string" },
+                        "count": { "type": "integer" },
+                        "percentage": { "type": "number" },
+                        "rank": { "type": "integer" },
+                        "unicode": { "type": "string" },
+                        "found": { "type": "boolean" }
+                    }
+                },
+                "SearchTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string" },
+                        "mode": { "type": "string", "enum": ["substring", "prefix", "regex"] },
+                        "results": { "type": "array", "items": schema_ref("DomainTemplate") }
+                    }
+                },
+                "HistoryPoint": {
+                    "type": "object",
+                    "properties": {
+                        "date": { "type": "string", "format": "date" },
+                        "total": { "type": "integer" }
+                    }
+                },
+                "AvgPerDomainPoint": {
+                    "type": "object",
+                    "properties": {
+                        "date": { "type": "string", "format": "date" },
+                        "total": { "type": "integer" },
+                        "domains": { "type": "integer" },
+                        "average": { "type": "number" }
+                    }
+                },
+                "Spike": {
+                    "type": "object",
+                    "properties": {
+                        "date": { "type": "string", "format": "date" },
+                        "previous": { "type": "string", "format": "date" },
+                        "delta": { "type": "integer" }
+                    }
+                },
+                "DomainHistoryPoint": {
+                    "type": "object",
+                    "properties": {
+                        "date": { "type": "string", "format": "date" },
+                        "count": { "type": "integer" }
+                    }
+                },
+                "RankHistoryPoint": {
+                    "type": "object",
+                    "properties": {
+                        "date": { "type": "string", "format": "date" },
+                        "rank": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Not set if the domain isn't in the dump"
+                        }
+                    }
+                },
+                "GrowthTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "from": { "type": "string", "format": "date" },
+                        "to": { "type": "string", "format": "date" },
+                        "domains": { "type": "array", "items": schema_ref("GrowthEntry") }
+                    }
+                },
+                "GrowthRateTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "from": { "type": "string", "format": "date" },
+                        "to": { "type": "string", "format": "date" },
+                        "from_total": { "type": "integer" },
+                        "to_total": { "type": "integer" },
+                        "growth": { "type": "integer" },
+                        "days": { "type": "integer" },
+                        "per_day": {
+                            "type": "number",
+                            "nullable": true,
+                            "description": "Average short URLs added per day"
+                        }
+                    }
+                },
+                "RangeTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "from": { "type": "string", "format": "date" },
+                        "to": { "type": "string", "format": "date" },
+                        "total": { "type": "integer" },
+                        "stats": { "type": "array", "items": schema_ref("DomainTemplate") }
+                    }
+                },
+                "GrowthEntry": {
+                    "type": "object",
+                    "properties": {
+                        "domain": { "type": "string" },
+                        "old_count": { "type": "integer" },
+                        "new_count": { "type": "integer" },
+                        "delta": { "type": "integer" },
+                        "pct_change": { "type": "number", "nullable": true },
+                        "direction": {
+                            "type": "string",
+                            "enum": ["up", "down", "same", "new", "gone"]
+                        }
+                    }
+                },
+                "DomainDiffTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "from": { "type": "string", "format": "date", "nullable": true },
+                        "to": { "type": "string", "format": "date", "nullable": true },
+                        "domains": { "type": "array", "items": schema_ref("DomainTemplate") },
+                        "note": { "type": "string" }
+                    }
+                },
+                "FirstSeenTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "domain": { "type": "string" },
+                        "date": { "type": "string", "format": "date" }
+                    }
+                },
+                "CodeLengthsPoint": {
+                    "type": "object",
+                    "properties": {
+                        "date": { "type": "string", "format": "date" },
+                        "lengths": {
+                            "type": "object",
+                            "description": "Number of short codes, keyed by length",
+                            "additionalProperties": { "type": "integer" }
+                        }
+                    }
+                },
+                "ChartSeriesTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "total": { "type": "array", "items": schema_ref("ChartPoint") },
+                        "domain": { "type": "array", "items": schema_ref("ChartPoint") }
+                    }
+                },
+                "ChartPoint": {
+                    "type": "array",
+                    "description": "Date and count",
+                    "items": { "oneOf": [{ "type": "string", "format": "date" }, { "type": "integer" }] },
+                    "minItems": 2,
+                    "maxItems": 2
+                },
+                "DashboardTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "index": schema_ref("IndexTemplate"),
+                        "dates": schema_ref("DatesTemplate"),
+                        "history": { "type": "array", "items": schema_ref("ChartPoint") }
+                    }
+                },
+                "BreakdownTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "total": { "type": "integer" },
+                        "wikimedia": { "type": "integer" },
+                        "external": { "type": "integer" },
+                        "wikimedia_share": { "type": "number" }
+                    }
+                },
+                "DatesTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "dates": { "type": "array", "items": { "type": "string", "format": "date" } },
+                        "min": { "type": "string", "format": "date", "nullable": true },
+                        "max": { "type": "string", "format": "date", "nullable": true }
+                    }
+                },
+                "SummaryTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "total": { "type": "integer" },
+                        "distinct_domains": { "type": "integer" },
+                        "mean": { "type": "number" },
+                        "p50": { "type": "integer" },
+                        "p90": { "type": "integer" },
+                        "p99": { "type": "integer" },
+                        "top10_share": { "type": "number" }
+                    }
+                },
+                "ConcentrationTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "date": { "type": "string", "format": "date" },
+                        "total": { "type": "integer" },
+                        "distinct_domains": { "type": "integer" },
+                        "gini": { "type": "number", "minimum": 0, "maximum": 1 },
+                        "top1pct_domains": { "type": "integer" },
+                        "top1pct_share": { "type": "number" }
+                    }
+                },
+                "TldEntry": {
+                    "type": "object",
+                    "properties": {
+                        "tld": { "type": "string" },
+                        "count": { "type": "integer" },
+                        "domains": { "type": "integer" }
+                    }
+                },
+                "ProjectEntry": {
+                    "type": "object",
+                    "properties": {
+                        "project": { "type": "string" },
+                        "count": { "type": "integer" },
+                        "domains": { "type": "integer" }
+                    }
+                },
+                "ErrorTemplate": {
+                    "type": "object",
+                    "properties": {
+                        "error": { "type": "string" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[get("/feed.atom")]
+async fn feed_atom() -> Result<(ContentType, String), AppError> {
+    Ok((
+        ContentType::new("application", "atom+xml"),
+        build_feed().await?,
+    ))
+}
+
+/// Build an Atom feed with an entry for each data file, newest first
+async fn build_feed() -> Result<String> {
+    let client = connect_redis()?;
+    let dated = dated_data()?;
+    // Load one extra data file so the oldest entry has something to compare against
+    let skip = dated.len().saturating_sub(FEED_ENTRIES + 1);
+    let mut totals = Vec::new();
+    for (date, path) in dated.into_iter().skip(skip) {
+        totals.push((date, get_data(path, &client).await?.total));
+    }
+    let updated = match totals.last() {
+        Some((date, _)) => *date,
+        None => Utc::now().date_naive(),
+    };
+    let mut entries = Vec::new();
+    for (idx, (date, total)) in totals.iter().enumerate().rev().take(FEED_ENTRIES) {
+        let summary = match idx.checked_sub(1).map(|prev| totals[prev].1) {
+            Some(prev) => format!(
+                "{} short URLs ({:+} since the previous dump)",
+                total.separate_with_commas(),
+                total - prev
+            ),
+            None => format!("{} short URLs", total.separate_with_commas()),
+        };
+        entries.push(format!(
+            r#"  <entry>
+    <title>w.wiki statistics for {date}</title>
+    <id>{base}#{date}</id>
+    <link href="{base}"/>
+    <updated>{date}T00:00:00Z</updated>
+    <summary>{summary}</summary>
+  </entry>
+"#,
+            base = BASE_URL,
+            date = date,
+            summary = summary
+        ));
+    }
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>w.wiki statistics</title>
+  <id>{base}</id>
+  <link href="{base}"/>
+  <link rel="self" href="{base}feed.atom"/>
+  <updated>{updated}T00:00:00Z</updated>
+  <author><name>shorturls</name></author>
+{entries}</feed>
+"#,
+        base = BASE_URL,
+        updated = updated,
+        entries = entries.concat()
+    ))
+}
+
+/// Most URLs the sitemap spec allows in a single sitemap
+const SITEMAP_LIMIT: usize = 50_000;
+
+/// Pages listed in the sitemap besides the domain pages
+const SITEMAP_PAGES: &[&str] = &["", "dates.json", "domains.json", "api.json", "feed.atom"];
+
+#[get("/sitemap.xml?<page>")]
+async fn sitemap(page: Option<usize>) -> Result<(ContentType, String), AppError> {
+    let latest = get_latest_data()?;
+    let date = parse_date(latest.file_name().unwrap().to_str().unwrap())?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    let base = url::Url::parse(BASE_URL).map_err(anyhow::Error::from)?;
+    let mut urls: Vec<String> = SITEMAP_PAGES
+        .iter()
+        .map(|page| format!("{}{}", BASE_URL, page))
+        .collect();
+    for dinfo in data.stats {
+        let mut url = base.clone();
+        url.set_path(&dinfo.domain);
+        urls.push(url.into());
+    }
+    Ok((ContentType::XML, build_sitemap(&urls, date, page)?))
+}
+
+/// Build a sitemap listing the URLs. If there are too many for one
+/// sitemap, a sitemap index pointing at each `?page=N` is returned
+/// instead.
+fn build_sitemap(
+    urls: &[String],
+    lastmod: NaiveDate,
+    page: Option<usize>,
+) -> Result<String, AppError> {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let pages = urls.len().div_ceil(SITEMAP_LIMIT).max(1);
+    let urls = match page {
+        None if pages > 1 => {
+            out.push_str("<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+            for num in 1..=pages {
+                out.push_str(&format!(
+                    "  <sitemap><loc>{}sitemap.xml?page={}</loc><lastmod>{}</lastmod></sitemap>\n",
+                    BASE_URL, num, lastmod
+                ));
+            }
+            out.push_str("</sitemapindex>\n");
+            return Ok(out);
+        }
+        None => urls,
+        Some(num) if (1..=pages).contains(&num) => {
+            let start = (num - 1) * SITEMAP_LIMIT;
+            &urls[start..(start + SITEMAP_LIMIT).min(urls.len())]
+        }
+        Some(num) => {
+            return Err(AppError::NotFound(format!(
+                "No sitemap page {}, there are {}",
+                num, pages
+            )))
+        }
+    };
+    out.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for url in urls {
+        out.push_str(&format!(
+            "  <url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+            xml_escape(url),
+            lastmod
+        ));
+    }
+    out.push_str("</urlset>\n");
+    Ok(out)
+}
+
+/// Escape text for use in XML
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[get("/metrics")]
+async fn metrics() -> Result<(ContentType, String), AppError> {
+    Ok((
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        build_metrics().await?,
+    ))
+}
+
+/// Build metrics in the Prometheus text exposition format
+async fn build_metrics() -> Result<String> {
+    let files = find_data()?;
+    let mut out = String::new();
+    if let Some(latest) = files.last() {
+        let client = connect_redis()?;
+        let data = get_data(latest.clone(), &client).await?;
+        out.push_str(&format!(
+            "# HELP shorturls_total Number of short URLs in the latest dump\n\
+             # TYPE shorturls_total gauge\n\
+             shorturls_total {}\n\
+             # HELP shorturls_domains Number of distinct domains in the latest dump\n\
+             # TYPE shorturls_domains gauge\n\
+             shorturls_domains {}\n",
+            data.total,
+            data.stats.len()
+        ));
+    }
+    out.push_str(&format!(
+        "# HELP shorturls_data_files Number of available data files\n\
+         # TYPE shorturls_data_files gauge\n\
+         shorturls_data_files {}\n\
+         # HELP shorturls_cache_hits Number of data files loaded from Redis\n\
+         # TYPE shorturls_cache_hits counter\n\
+         shorturls_cache_hits {}\n\
+         # HELP shorturls_cache_misses Number of data files not found in Redis\n\
+         # TYPE shorturls_cache_misses counter\n\
+         shorturls_cache_misses {}\n",
+        files.len(),
+        CACHE_HITS.load(Ordering::Relaxed),
+        CACHE_MISSES.load(Ordering::Relaxed)
+    ));
+    Ok(out)
+}
+
+/// Parse a `YYYY-MM-DD` date provided in a query parameter
+fn parse_query_date(date: &str) -> Result<NaiveDate, AppError> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| AppError::BadRequest(format!("Invalid date: {} (expected YYYY-MM-DD)", date)))
+}
+
+/// CSV export of the latest domain ranking, served as a download
+#[derive(Responder)]
+struct CsvExport<S> {
+    inner: (ContentType, TextStream<S>),
+    disposition: Header<'static>,
+}
+
+/// The ranking is loaded up front so errors are reported before any of the
+/// body is sent; the rows are formatted as they're streamed out.
+#[get("/export.csv")]
+async fn export_csv() -> Result<CsvExport<impl rocket::futures::Stream<Item = String>>, AppError> {
+    let (filename, data) = match find_data()?.pop() {
+        Some(latest) => {
+            let date = parse_date(latest.file_name().unwrap().to_str().unwrap())?;
+            let client = connect_redis()?;
+            let data = get_data(latest, &client).await?;
+            (
+                format!("shorturls-{}.csv", date.format("%Y%m%d")),
+                Some(data),
+            )
+        }
+        // No data yet, just send the header
+        None => ("shorturls.csv".to_string(), None),
+    };
+    let stream = TextStream! {
+        yield "domain,count\n".to_string();
+        for row in data.into_iter().flat_map(csv_rows) {
+            yield row;
+        }
+    };
+    Ok(CsvExport {
+        inner: (ContentType::CSV, stream),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        ),
+    })
+}
+
+/// CSV rows for each domain by count, then the total if there are any
+fn csv_rows(mut data: IndexTemplate) -> impl Iterator<Item = String> {
+    data.stats
+        .sort_by_key(|dinfo| std::cmp::Reverse(dinfo.count));
+    let total = (!data.stats.is_empty()).then(|| format!("total,{}\n", data.total));
+    data.stats
+        .into_iter()
+        .map(|dinfo| format!("{},{}\n", csv_field(&dinfo.domain), dinfo.count))
+        .chain(total)
+}
+
+/// Latest domain ranking as JSON Lines, starting with a header object
+/// holding the total and date, then one line per domain. The ranking is
+/// loaded and serialized up front so errors are reported before any of
+/// the body is sent; only writing it out is streamed.
+#[get("/export.jsonl")]
+async fn export_jsonl() -> Result<(ContentType, TextStream![String]), AppError> {
+    let latest = get_latest_data()?;
+    let date = parse_date(latest.file_name().unwrap().to_str().unwrap())?;
+    let client = connect_redis()?;
+    let data = get_data(latest, &client).await?;
+    let header = json!({
+        "total": data.total,
+        "date": date.format("%Y-%m-%d").to_string(),
+    });
+    let lines = data
+        .stats
+        .iter()
+        .map(|dinfo| serde_json::to_string(dinfo).map(|line| format!("{}\n", line)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let stream = TextStream! {
+        yield format!("{}\n", header);
+        for line in lines {
+            yield line;
+        }
+    };
+    Ok((ContentType::new("application", "x-ndjson"), stream))
+}
+
+/// Raw data file for a date, served verbatim as a download
+#[derive(Responder)]
+struct RawData {
+    inner: (ContentType, Vec<u8>),
+    disposition: Header<'static>,
+}
+
+/// Download the unprocessed data file for a date (`/raw/YYYYMMDD.data`)
+#[get("/raw/<file>", rank = 2)]
+async fn raw_data(file: &str) -> Result<RawData, AppError> {
+    let path = data_for_date(parse_raw_name(file)?)?;
+    let body = fs::read(&path).await?;
+    let filename = path.file_name().unwrap().to_string_lossy().to_string();
+    Ok(RawData {
+        inner: (ContentType::JSON, body),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        ),
+    })
+}
+
+/// Parse the date out of a `YYYYMMDD.data` raw download name
+fn parse_raw_name(file: &str) -> Result<NaiveDate, AppError> {
+    match file.strip_suffix(".data") {
+        Some(date) => parse_path_date(date),
+        None => Err(AppError::NotFound(format!("Unknown file: {}", file))),
+    }
+}
+
+/// Quote a CSV field if it contains any special characters
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Favicon, embedded so it's always available
+const FAVICON: &[u8] = include_bytes!("../static/favicon.ico");
+
+/// Response wrapper for the favicon so it can carry a long cache header
+#[derive(Responder)]
+struct Favicon {
+    inner: (ContentType, &'static [u8]),
+    cache_control: Header<'static>,
+}
+
+#[get("/favicon.ico")]
+fn favicon() -> Favicon {
+    Favicon {
+        inner: (ContentType::Icon, FAVICON),
+        // One week
+        cache_control: Header::new("Cache-Control", "public, max-age=604800"),
+    }
+}
+
+#[get("/healthz")]
+async fn healthz() -> Result<&'static str, (Status, String)> {
+    match check_health().await {
+        Ok(()) => Ok("OK"),
+        Err(err) => Err((Status::ServiceUnavailable, err.to_string())),
+    }
+}
+
+/// Make sure Redis is reachable and there is data to serve, without
+/// actually loading any of it
+async fn check_health() -> Result<()> {
+    let mut conn = connect_redis()?
+        .get_async_connection()
+        .await
+        .map_err(|err| anyhow!("Unable to connect to Redis: {}", err))?;
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut conn)
+        .await
+        .map_err(|err| anyhow!("Redis ping failed: {}", err))?;
+    get_latest_data()?;
+    Ok(())
+}
+
+/// When the server started
+struct Started(Instant);
+
+/// Internal state, for working out why the site is showing old data
+#[derive(Serialize)]
+struct DebugTemplate {
+    data_files: usize,
+    latest_date: Option<String>,
+    redis_connected: bool,
+    /// Redis key the latest data file is cached under
+    cache_key: Option<String>,
+    /// Whether the latest data file is in Redis, if that can be checked
+    cache_populated: Option<bool>,
+    cache_hits: u64,
+    cache_misses: u64,
+    uptime_secs: u64,
+}
+
+/// Only mounted if `SHORTURLS_DEBUG` is set
+#[get("/debug.json")]
+async fn debug_api(started: &State<Started>) -> Result<Json<DebugTemplate>, AppError> {
+    let files = find_data()?;
+    let latest = files.last();
+    let mut conn = connect_redis()?.get_async_connection().await.ok();
+    let cache_key = latest.map(|latest| data_cache_key(latest));
+    let cache_populated = match (conn.as_mut(), &cache_key) {
+        (Some(conn), Some(key)) => Some(conn.exists(key).await?),
+        _ => None,
+    };
+    Ok(Json(DebugTemplate {
+        data_files: files.len(),
+        latest_date: latest
+            .and_then(|latest| parse_date(latest.file_name()?.to_str()?).ok())
+            .map(|date| date.to_string()),
+        redis_connected: conn.is_some(),
+        cache_key,
+        cache_populated,
+        cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+        cache_misses: CACHE_MISSES.load(Ordering::Relaxed),
+        uptime_secs: started.0.elapsed().as_secs(),
+    }))
+}
+
+/// Fairing that allows the JSON API to be used from other origins
+struct Cors;
+
+/// Whether a path is part of the JSON API, and so gets CORS headers
+fn is_api_path(path: &str) -> bool {
+    path.ends_with(".json") || path == "/autocomplete"
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS headers for the JSON API",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, resp: &mut Response<'r>) {
+        if !is_api_path(req.uri().path().as_str()) {
+            return;
+        }
+        resp.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        resp.set_header(Header::new("Access-Control-Allow-Methods", "GET, OPTIONS"));
+        resp.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type"));
+        resp.set_header(Header::new("Access-Control-Max-Age", "86400"));
+        // There are no OPTIONS routes, so turn the 404 for a preflight
+        // request into an empty success
+        if req.method() == Method::Options {
+            resp.set_status(Status::NoContent);
+            resp.set_sized_body(0, std::io::Cursor::new(""));
+            resp.remove_header("Content-Type");
+        }
+    }
+}
+
+/// How many data files to load into Redis at once while warming the cache
+const WARM_CACHE_CONCURRENCY: usize = 4;
+
+/// Load every data file into Redis, so the first requests after a deploy
+/// don't all have to read from disk
+async fn warm_cache() {
+    use rocket::futures::StreamExt;
+    let (files, client) = match (find_data(), connect_redis().map_err(anyhow::Error::from)) {
+        (Ok(files), Ok(client)) => (files, client),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Unable to warm cache: {}", err);
+            return;
+        }
+    };
+    let count = files.len();
+    rocket::futures::stream::iter(files)
+        .for_each_concurrent(WARM_CACHE_CONCURRENCY, |path| async {
+            let display = path.display().to_string();
+            if let Err(err) = get_data(path, &client).await {
+                warn!("Unable to warm cache for {}: {}", display, err);
+            }
+        })
+        .await;
+    info!("Warmed cache with {} data files", count);
+}
+
+/// Fairing that compresses text responses for clients that accept it
+struct Compression;
+
+/// Responses smaller than this aren't worth compressing
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Content encodings `Compression` can use
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Write;
+        let level = flate2::Compression::default();
+        match self {
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Pick an encoding from an `Accept-Encoding` header, preferring gzip.
+/// Encodings with a quality of 0 are refused by the client.
+fn pick_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let accepted: Vec<&str> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next()?;
+            let refused = parts.any(|param| {
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            (!refused).then_some(name)
+        })
+        .collect();
+    [Encoding::Gzip, Encoding::Deflate]
+        .iter()
+        .copied()
+        .find(|encoding| accepted.contains(&encoding.name()))
+}
+
+/// Whether a response is text that compresses well, unlike e.g. PNGs
+fn is_compressible(content_type: &ContentType) -> bool {
+    content_type.is_json()
+        || content_type.is_csv()
+        || content_type.is_svg()
+        || content_type.is_xml()
+        || content_type.is_html()
+        || content_type.is_plain()
+}
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, resp: &mut Response<'r>) {
+        if !resp.content_type().is_some_and(|ct| is_compressible(&ct))
+            || resp.headers().contains("Content-Encoding")
+        {
+            return;
+        }
+        // Streamed responses don't have a size and are left alone
+        if resp
+            .body()
+            .preset_size()
+            .is_none_or(|size| size < MIN_COMPRESS_SIZE)
+        {
+            return;
+        }
+        let vary = match resp.headers().get_one("Vary") {
+            Some(vary) => format!("{}, Accept-Encoding", vary),
+            None => "Accept-Encoding".to_string(),
+        };
+        resp.set_raw_header("Vary", vary);
+        let encoding = match req.headers().get("Accept-Encoding").find_map(pick_encoding) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        let body = match resp.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Unable to read response body for compression: {}", err);
+                return;
+            }
+        };
+        match encoding.compress(&body) {
+            Ok(compressed) => {
+                resp.set_sized_body(compressed.len(), std::io::Cursor::new(compressed));
+                resp.set_raw_header("Content-Encoding", encoding.name());
+                // The compressed bytes differ, so the entity tag is only
+                // weakly equal to the uncompressed response's
+                if let Some(etag) = resp.headers().get_one("ETag") {
+                    if !etag.starts_with("W/") {
+                        let weak = format!("W/{}", etag);
+                        resp.set_raw_header("ETag", weak);
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("Unable to compress response: {}", err);
+                resp.set_sized_body(body.len(), std::io::Cursor::new(body));
+            }
+        }
+    }
+}
+
+/// Send the webserver's diagnostics, and Rocket's own launch and request
+/// logs, through env_logger. Rocket's `log_level` sets the default, which
+/// `RUST_LOG` can override.
+fn init_logging(figment: &Figment) {
+    let level = match figment.extract_inner::<LogLevel>("log_level") {
+        Ok(LogLevel::Off) => LevelFilter::Off,
+        Ok(LogLevel::Critical) => LevelFilter::Warn,
+        Ok(LogLevel::Debug) => LevelFilter::Debug,
+        Ok(LogLevel::Normal) | Err(_) => LevelFilter::Info,
+    };
+    // Tests build the server more than once, but the logger can only be set once
+    let _ = env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .try_init();
+}
+
+/// Rocket's usual configuration (`Rocket.toml` and `ROCKET_*`), with
+/// `SHORTURLS_PORT` and `SHORTURLS_ADDRESS` on top so several instances
+/// can easily be run side by side
+fn figment() -> Figment {
+    listen_on(
+        rocket::Config::figment(),
+        std::env::var("SHORTURLS_PORT").ok(),
+        std::env::var("SHORTURLS_ADDRESS").ok(),
+    )
+}
+
+/// Override the port and address to listen on, ignoring invalid values
+fn listen_on(figment: Figment, port: Option<String>, address: Option<String>) -> Figment {
+    let figment = match port.and_then(|port| port.parse::<u16>().ok()) {
+        Some(port) => figment.merge(("port", port)),
+        None => figment,
+    };
+    match address.and_then(|address| address.parse::<IpAddr>().ok()) {
+        Some(address) => figment.merge(("address", address)),
+        None => figment,
+    }
+}
+
+#[launch]
+fn rocket() -> _ {
+    let figment = figment();
+    init_logging(&figment);
+    rocket::custom(figment)
+        .attach(Template::custom(|engines| {
+            engines.tera.register_function("commafy", Box::new(commafy));
+        }))
+        .attach(Cors)
+        .attach(Compression)
+        .manage(RateLimiter::from_env())
+        .manage(Started(Instant::now()))
+        // Opt-in since it delays startup until everything is loaded
+        .attach(AdHoc::on_ignite("Warm cache", |rocket| async {
+            if std::env::var_os("SHORTURLS_WARM_CACHE").is_some() {
+                warm_cache().await;
+            }
+            rocket
+        }))
+        // Opt-in since it exposes internal state
+        .attach(AdHoc::on_ignite("Debug endpoint", |rocket| async {
+            if std::env::var_os("SHORTURLS_DEBUG").is_some() {
+                rocket.mount("/", routes![debug_api])
+            } else {
+                rocket
+            }
+        }))
+        .mount(
+            "/",
+            routes![
+                index,
+                index_api,
+                date_index,
+                date_index_api,
+                chart_svg,
+                domain,
+                domain_api,
+                domain_chart_svg,
+                chart_grid_svg,
+                chart_png,
+                domain_chart_png,
+                chart_json,
+                domain_chart_json,
+                export_csv,
+                export_jsonl,
+                raw_data,
+                history_api,
+                spikes_api,
+                avg_per_domain_api,
+                codelengths_api,
+                domain_history_api,
+                domain_rank_history_api,
+                search,
+                search_api,
+                growth_api,
+                growth_rate_api,
+                compare,
+                compare_api,
+                range,
+                range_api,
+                tld_api,
+                multi_api,
+                projects_api,
+                new_api,
+                removed_api,
+                summary_api,
+                concentration_api,
+                domains_api,
+                autocomplete,
+                dates_api,
+                breakdown_api,
+                dashboard_api,
+                firstseen,
+                firstseen_api,
+                feed_atom,
+                sitemap,
+                metrics,
+                healthz,
+                favicon,
+                openapi,
+            ],
+        )
+        .mount("/static", FileServer::from("static"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_commafy() {
+        let mut map: HashMap<String, Value> = HashMap::new();
+        map.insert("num".to_string(), Value::String("9999999".to_string()));
+        let result = commafy(&map);
+        assert_eq!(Value::String("9,999,999".to_string()), result.unwrap());
+        map.insert("num".to_string(), Value::from(9999999));
+        let result = commafy(&map);
+        assert_eq!(Value::String("9,999,999".to_string()), result.unwrap());
+        map.insert("num".to_string(), Value::from(999));
+        let result = commafy(&map);
+        assert_eq!(Value::String("999".to_string()), result.unwrap());
+        map.insert("num".to_string(), Value::Null);
+        assert!(commafy(&map).is_err());
+    }
+
+    #[test]
+    fn test_commafy_separators() {
+        let commafy_with = |sep: &str| {
+            let mut map: HashMap<String, Value> = HashMap::new();
+            map.insert("num".to_string(), Value::from(1234567));
+            map.insert("sep".to_string(), Value::from(sep));
+            commafy(&map).unwrap()
+        };
+        assert_eq!(Value::from("1,234,567"), commafy_with(","));
+        assert_eq!(Value::from("1 234 567"), commafy_with(" "));
+        assert_eq!(Value::from("1.234.567"), commafy_with("."));
+        assert_eq!(Value::from("1\u{a0}234\u{a0}567"), commafy_with("\u{a0}"));
+        let mut map: HashMap<String, Value> = HashMap::new();
+        map.insert("num".to_string(), Value::from(1234));
+        map.insert("sep".to_string(), Value::from(1));
+        assert!(commafy(&map).is_err());
+    }
+
+    #[test]
+    fn test_app_error_status() {
+        assert_eq!(Status::NotFound, AppError::unknown_domain().status());
+        assert_eq!(
+            Status::BadRequest,
+            AppError::BadRequest("oops".to_string()).status()
+        );
+        assert_eq!(Status::ServiceUnavailable, AppError::NoData.status());
+        let err = AppError::from(anyhow!(AppError::NoData));
+        assert_eq!(Status::ServiceUnavailable, err.status());
+        assert_eq!(NO_DATA, err.to_string());
+        let err = AppError::from(anyhow!("oops"));
+        assert_eq!(Status::InternalServerError, err.status());
+        let err = AppError::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(Status::InternalServerError, err.status());
+    }
+
+    #[test]
+    fn test_index_options() {
+        let opts = IndexOptions::default();
+        assert!(opts.validate().is_ok());
+        assert_eq!(1, opts.min_count());
+        let opts = IndexOptions {
+            min: Some(10),
+            ..Default::default()
+        };
+        assert!(opts.validate().is_ok());
+        assert_eq!(10, opts.min_count());
+        let opts = IndexOptions {
+            min: Some(0),
+            ..Default::default()
+        };
+        assert!(opts.validate().is_err());
+        let opts = IndexOptions {
+            limit: Some(0),
+            ..Default::default()
+        };
+        assert!(opts.validate().is_err());
+    }
+
+    #[test]
+    fn test_build_sitemap() {
+        let lastmod = NaiveDate::from_ymd_opt(2020, 1, 31).unwrap();
+        let urls = vec![
+            BASE_URL.to_string(),
+            format!("{}a.org", BASE_URL),
+            format!("{}b.org?x=1&y=2", BASE_URL),
+        ];
+        let xml = build_sitemap(&urls, lastmod, None).unwrap();
+        assert!(xml.contains("<urlset"));
+        assert_eq!(3, xml.matches("<url>").count());
+        assert!(xml.contains("b.org?x=1&amp;y=2</loc><lastmod>2020-01-31</lastmod>"));
+        assert_eq!(xml, build_sitemap(&urls, lastmod, Some(1)).unwrap());
+        assert_eq!(
+            Status::NotFound,
+            build_sitemap(&urls, lastmod, Some(2)).unwrap_err().status()
+        );
+        // Too many for one sitemap
+        let urls = vec![BASE_URL.to_string(); SITEMAP_LIMIT + 1];
+        let xml = build_sitemap(&urls, lastmod, None).unwrap();
+        assert!(xml.contains("<sitemapindex"));
+        assert!(xml.contains("sitemap.xml?page=2</loc>"));
+        assert!(!xml.contains("page=3"));
+        let xml = build_sitemap(&urls, lastmod, Some(2)).unwrap();
+        assert_eq!(1, xml.matches("<url>").count());
+    }
+
+    #[test]
+    fn test_find_spikes() {
+        let history: Vec<HistoryPoint> = [
+            ("2020-01-01", 10),
+            ("2020-01-02", 15),
+            ("2020-01-03", 12),
+            ("2020-01-04", 20),
+            ("2020-01-05", 25),
+        ]
+        .iter()
+        .map(|(date, total)| HistoryPoint {
+            date: date.to_string(),
+            total: *total,
+        })
+        .collect();
+        let spike = |date: &str, previous: &str, delta| Spike {
+            date: date.to_string(),
+            previous: previous.to_string(),
+            delta,
+        };
+        assert_eq!(
+            vec![
+                spike("2020-01-04", "2020-01-03", 8),
+                spike("2020-01-05", "2020-01-04", 5),
+                spike("2020-01-02", "2020-01-01", 5),
+            ],
+            find_spikes(&history)
+        );
+        assert!(find_spikes(&history[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let date = NaiveDate::from_ymd_opt(2020, 1, 31).unwrap();
+        assert_eq!(date, parse_date("shorturls-20200131.gz.data").unwrap());
+        assert_eq!(date, parse_date("shorturls-20200131.bz2.data").unwrap());
+        for name in [
+            "shorturls-20200131.xz.data",
+            "shorturls-20201331.gz.data",
+            "shorturls-20200131.gz",
+            "garbage",
+        ] {
+            let err = parse_date(name).unwrap_err();
+            assert_eq!(
+                format!("Unrecognized data file name: {}", name),
+                err.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_raw_name() {
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2020, 1, 31).unwrap(),
+            parse_raw_name("20200131.data").unwrap()
+        );
+        assert_eq!(
+            Status::BadRequest,
+            parse_raw_name("20201331.data").unwrap_err().status()
+        );
+        assert_eq!(
+            Status::NotFound,
+            parse_raw_name("20200131.json").unwrap_err().status()
+        );
+    }
+
+    #[test]
+    fn test_paginate() {
+        let stats = [
+            ("a.org", 5),
+            ("b.org", 4),
+            ("c.org", 3),
+            ("d.org", 2),
+            ("e.org", 1),
+        ];
+        let opts = IndexOptions {
+            sort: Some(SortKey::Domain),
+            ..Default::default()
+        };
+        let page = paginate(IndexPage::new(index(&stats)), &opts, None, None).unwrap();
+        assert_eq!(5, page.index.stats.len());
+        assert!(page.pagination.is_none());
+        let page = paginate(
+            IndexPage::new(index(&stats)),
+            &opts,
+            Some(2),
+            Some(PerPage::Count(2)),
+        )
+        .unwrap();
+        let domains: Vec<&str> = page.index.stats.iter().map(|d| d.domain.as_str()).collect();
+        assert_eq!(vec!["c.org", "d.org"], domains);
+        assert_eq!(5, page.index.distinct_domains);
+        assert_eq!(
+            Some(Pagination {
+                page: 2,
+                pages: 3,
+                per_page: 2,
+                prev: Some("?sort=domain&page=1&per_page=2".to_string()),
+                next: Some("?sort=domain&page=3&per_page=2".to_string()),
+                all: "?sort=domain&per_page=all".to_string(),
+            }),
+            page.pagination
+        );
+        let page = paginate(
+            IndexPage::new(index(&stats)),
+            &opts,
+            Some(3),
+            Some(PerPage::Count(2)),
+        )
+        .unwrap();
+        assert_eq!(1, page.index.stats.len());
+        assert!(page.pagination.unwrap().next.is_none());
+        let page = paginate(
+            IndexPage::new(index(&stats)),
+            &opts,
+            Some(2),
+            Some(PerPage::All),
+        )
+        .unwrap();
+        assert_eq!(5, page.index.stats.len());
+        // Only the page given
+        let page = paginate(IndexPage::new(index(&stats)), &opts, Some(1), None).unwrap();
+        assert_eq!(DEFAULT_PER_PAGE, page.pagination.unwrap().per_page);
+        let err = paginate(
+            IndexPage::new(index(&stats)),
+            &opts,
+            Some(4),
+            Some(PerPage::Count(2)),
+        );
+        assert_eq!(Status::NotFound, err.err().unwrap().status());
+        let err = paginate(
+            IndexPage::new(index(&stats)),
+            &opts,
+            None,
+            Some(PerPage::Count(0)),
+        );
+        assert_eq!(Status::BadRequest, err.err().unwrap().status());
+    }
+
+    #[test]
+    fn test_pick_domains() {
+        let mut data = index(&[("a.org", 3), ("b.org", 2), ("c.org", 1)]);
+        data.compute_ranks();
+        let picked = pick_domains(&data, &["c.org", "missing.org", "a.org", "c.org"]);
+        let summary: Vec<(&str, i32, usize, bool)> = picked
+            .iter()
+            .map(|entry| {
+                (
+                    entry.domain.domain.as_str(),
+                    entry.domain.count,
+                    entry.domain.rank,
+                    entry.found,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("c.org", 1, 3, true),
+                ("missing.org", 0, 0, false),
+                ("a.org", 3, 1, true),
+                ("c.org", 1, 3, true)
+            ],
+            summary
+        );
+    }
+
+    #[test]
+    fn test_pick_encoding() {
+        assert_eq!(Some(Encoding::Gzip), pick_encoding("gzip, deflate, br"));
+        assert_eq!(
+            Some(Encoding::Gzip),
+            pick_encoding("deflate;q=1.0, gzip;q=0.5")
+        );
+        assert_eq!(Some(Encoding::Deflate), pick_encoding("br, deflate"));
+        assert_eq!(Some(Encoding::Deflate), pick_encoding("gzip;q=0, deflate"));
+        assert_eq!(None, pick_encoding("identity"));
+        assert_eq!(None, pick_encoding(""));
+    }
+
+    #[test]
+    fn test_encoding_compress() {
+        use std::io::Read;
+        let body = "{\"domain\":\"en.wikipedia.org\"}".repeat(100);
+        let compressed = Encoding::Gzip.compress(body.as_bytes()).unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(body, decoded);
+        let compressed = Encoding::Deflate.compress(body.as_bytes()).unwrap();
+        let mut decoded = String::new();
+        flate2::read::ZlibDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(body, decoded);
+    }
+
+    #[test]
+    fn test_redis_url() {
+        assert_eq!("redis://127.0.0.1:6379/", redis_url(None, false));
+        assert_eq!("redis://tools-redis:6379/", redis_url(None, true));
+        assert_eq!(
+            "redis://:secret@example.org:6380/3",
+            redis_url(Some("redis://:secret@example.org:6380/3".to_string()), true)
+        );
+        assert_eq!(
+            "redis://tools-redis:6379/",
+            redis_url(Some("".to_string()), true)
+        );
+    }
+
+    #[test]
+    fn test_redis_retry_delay() {
+        assert_eq!(Duration::from_millis(50), redis_retry_delay(1));
+        assert_eq!(Duration::from_millis(100), redis_retry_delay(2));
+        assert_eq!(Duration::from_millis(200), redis_retry_delay(3));
+    }
+
+    #[rocket::async_test]
+    async fn test_connect_with_retries() {
+        use std::cell::Cell;
+        let health = RedisHealth::new();
+        let tries = Cell::new(0);
+        let fail = || {
+            tries.set(tries.get() + 1);
+            async { Err::<(), _>("connection refused") }
+        };
+        // Retry until giving up, then only try once during the cooldown
+        assert!(connect_with_retries(&health, fail).await.is_err());
+        assert_eq!(REDIS_ATTEMPTS, tries.replace(0));
+        assert!(connect_with_retries(&health, fail).await.is_err());
+        assert_eq!(1, tries.replace(0));
+        assert_eq!(
+            REDIS_ATTEMPTS,
+            health.attempts(Instant::now() + REDIS_COOLDOWN)
+        );
+        // Connecting again ends the cooldown
+        let succeed = || async { Ok::<_, &str>(()) };
+        assert!(connect_with_retries(&health, succeed).await.is_ok());
+        assert_eq!(REDIS_ATTEMPTS, health.attempts(Instant::now()));
+        // A panic while the lock was held doesn't break it
+        std::thread::scope(|scope| {
+            let _ = scope
+                .spawn(|| {
+                    let _guard = health.lock();
+                    panic!("poisoning the lock");
+                })
+                .join();
+        });
+        assert!(health.down_until.is_poisoned());
+        health.failed(Instant::now());
+        assert_eq!(1, health.attempts(Instant::now()));
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!("en.wikipedia.org", csv_field("en.wikipedia.org"));
+        assert_eq!("\"a,b\"", csv_field("a,b"));
+        assert_eq!("\"a\"\"b\"", csv_field("a\"b"));
+    }
+
+    #[test]
+    fn test_csv_rows() {
+        let data = index(&[("b.org", 3), ("a,org", 5)]);
+        assert_eq!(
+            vec!["\"a,org\",5\n", "b.org,3\n", "total,8\n"],
+            csv_rows(data).collect::<Vec<_>>()
+        );
+        assert_eq!(0, csv_rows(index(&[])).count());
+    }
+
+    #[test]
+    fn test_parse_query_date() {
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2020, 1, 31).unwrap(),
+            parse_query_date("2020-01-31").unwrap()
+        );
+        let err = parse_query_date("20200131").unwrap_err();
+        assert_eq!(Status::BadRequest, err.status());
+    }
+
+    fn index(stats: &[(&str, i32)]) -> IndexTemplate {
+        IndexTemplate {
+            schema_version: SCHEMA_VERSION,
+            stats: stats
+                .iter()
+                .map(|(domain, count)| DomainTemplate {
+                    domain: domain.to_string(),
+                    count: *count,
+                    percentage: 0.0,
+                    rank: 0,
+                    unicode: None,
+                })
+                .collect(),
+            total: stats.iter().map(|(_, count)| count).sum(),
+            distinct_domains: stats.len(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compare_counts() {
+        let old = index(&[("a.org", 10), ("b.org", 5), ("gone.org", 2)]);
+        let new = index(&[("a.org", 12), ("new.org", 4), ("b.org", 5)]);
+        let entries = compare_counts(&old, &new);
+        let summary: Vec<(&str, i32, i32, i32)> = entries
+            .iter()
+            .map(|e| (e.domain.as_str(), e.old_count, e.new_count, e.delta))
+            .collect();
+        assert_eq!(
+            vec![
+                ("new.org", 0, 4, 4),
+                ("a.org", 10, 12, 2),
+                ("b.org", 5, 5, 0),
+                ("gone.org", 2, 0, -2),
+            ],
+            summary
+        );
+        assert_eq!(None, entries[0].pct_change);
+        assert_eq!(Some(20.0), entries[1].pct_change);
+        assert_eq!(Some(-100.0), entries[3].pct_change);
+        let directions: Vec<Direction> = entries.iter().map(|e| e.direction).collect();
+        assert_eq!(
+            vec![
+                Direction::New,
+                Direction::Up,
+                Direction::Same,
+                Direction::Gone
+            ],
+            directions
+        );
+        assert_eq!(
+            Direction::Down,
+            growth_entry("a.org", Some(3), Some(1)).direction
+        );
+        assert_eq!(
+            json!("gone"),
+            serde_json::to_value(Direction::Gone).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let today = NaiveDate::from_ymd_opt(2020, 1, 8).unwrap();
+        let path = Path::new("./data/shorturls-20200108.gz.data");
+        assert_eq!(TODAY_CACHE_TTL, data_ttl(path, today, None));
+        assert_eq!(
+            TODAY_CACHE_TTL,
+            data_ttl(path, today, Some("60".to_string()))
+        );
+        let older = Path::new("./data/shorturls-20200101.gz.data");
+        assert_eq!(DEFAULT_CACHE_TTL, data_ttl(older, today, None));
+        assert_eq!(60, data_ttl(older, today, Some("60".to_string())));
+        assert_eq!(
+            DEFAULT_CACHE_TTL,
+            data_ttl(older, today, Some("forever".to_string()))
+        );
+        assert_eq!(
+            DEFAULT_CACHE_TTL,
+            data_ttl(older, today, Some("0".to_string()))
+        );
+        // Once the day is over, the file won't change any more
+        let tomorrow = today.succ_opt().unwrap();
+        assert_eq!(DEFAULT_CACHE_TTL, data_ttl(path, tomorrow, None));
+    }
+
+    #[test]
+    fn test_etag() {
+        let latest = Path::new("./data/shorturls-20200101.gz.data");
+        let tag = etag(latest, None, "/api.json");
+        assert!(tag.starts_with("\"20200101-"));
+        assert_eq!(tag, etag(latest, None, "/api.json"));
+        assert_ne!(tag, etag(latest, None, "/api.json?limit=10"));
+        assert_ne!(
+            tag,
+            etag(
+                Path::new("./data/shorturls-20200108.gz.data"),
+                None,
+                "/api.json"
+            )
+        );
+        // Rewriting the data file for the same date changes the tag
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shorturls-20200101.gz.data");
+        std::fs::write(&path, "{}").unwrap();
+        let before = std::fs::metadata(&path).unwrap();
+        std::fs::write(&path, "{\"total\": 1}").unwrap();
+        let after = std::fs::metadata(&path).unwrap();
+        let tag = etag(&path, Some(&before), "/api.json");
+        assert!(tag.starts_with("\"20200101-"));
+        assert_ne!(tag, etag(&path, Some(&after), "/api.json"));
+        assert!(etag_matches(&tag, &tag));
+        assert!(etag_matches(&format!("\"other\", W/{}", tag), &tag));
+        assert!(etag_matches("*", &tag));
+        assert!(!etag_matches("\"other\"", &tag));
+    }
+
+    #[test]
+    fn test_http_date() {
+        let date = DateTime::from_timestamp(1_577_880_000, 0).unwrap();
+        let formatted = http_date(date);
+        assert_eq!("Wed, 01 Jan 2020 12:00:00 GMT", formatted);
+        assert_eq!(date, DateTime::parse_from_rfc2822(&formatted).unwrap());
+    }
+
+    #[test]
+    fn test_drop_date() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let files = || {
+            vec![
+                PathBuf::from("data/shorturls-20200101.gz.data"),
+                PathBuf::from("data/shorturls-20200108.gz.data"),
+            ]
+        };
+        assert_eq!(files()[..1], drop_date(files(), date("2020-01-08"))[..]);
+        assert_eq!(files(), drop_date(files(), date("2020-01-01")));
+        assert!(drop_date(vec![], date("2020-01-01")).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_for() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let dated = vec![
+            (date("2020-01-01"), PathBuf::from("a")),
+            (date("2020-01-08"), PathBuf::from("b")),
+        ];
+        let found = |s| snapshot_for(&dated, date(s)).map(|(date, _)| date.to_string());
+        assert_eq!(Some("2020-01-01".to_string()), found("2019-06-01"));
+        assert_eq!(Some("2020-01-01".to_string()), found("2020-01-05"));
+        assert_eq!(Some("2020-01-08".to_string()), found("2020-01-08"));
+        assert_eq!(Some("2020-01-08".to_string()), found("2021-01-01"));
+        assert!(snapshot_for(&[], date("2020-01-01")).is_none());
+    }
+
+    #[test]
+    fn test_range_ranking() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let old = index(&[("a.org", 10), ("b.org", 5), ("gone.org", 1)]);
+        let new = index(&[("a.org", 11), ("b.org", 8), ("c.org", 4)]);
+        let ranking = range_ranking(date("2020-01-01"), date("2020-01-08"), &old, &new);
+        assert_eq!(8, ranking.total);
+        let stats: Vec<(&str, i32, usize)> = ranking
+            .stats
+            .iter()
+            .map(|dinfo| (dinfo.domain.as_str(), dinfo.count, dinfo.rank))
+            .collect();
+        assert_eq!(
+            vec![("c.org", 4, 1), ("b.org", 3, 2), ("a.org", 1, 3)],
+            stats
+        );
+        assert_eq!(50.0, ranking.stats[0].percentage);
+    }
+
+    #[test]
+    fn test_new_domains() {
+        let old = index(&[("a.org", 5), ("b.org", 3)]);
+        let new = index(&[("a.org", 6), ("c.org", 4), ("b.org", 3), ("d.org", 1)]);
+        let domains: Vec<(String, i32)> = new_domains(&old, new)
+            .into_iter()
+            .map(|dinfo| (dinfo.domain, dinfo.count))
+            .collect();
+        assert_eq!(
+            vec![("c.org".to_string(), 4), ("d.org".to_string(), 1)],
+            domains
+        );
+    }
+
+    #[test]
+    fn test_removed_domains() {
+        let old = index(&[("a.org", 5), ("b.org", 3), ("c.org", 2), ("d.org", 1)]);
+        let new = index(&[("a.org", 6), ("c.org", 0)]);
+        let domains: Vec<(String, i32)> = removed_domains(old, &new)
+            .into_iter()
+            .map(|dinfo| (dinfo.domain, dinfo.count))
+            .collect();
+        assert_eq!(
+            vec![
+                ("b.org".to_string(), 3),
+                ("c.org".to_string(), 2),
+                ("d.org".to_string(), 1)
+            ],
+            domains
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_read_data_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shorturls-20200101.gz.data");
+        std::fs::write(&path, "{\"stats\": [").unwrap();
+        let err = match read_data(&path).await {
+            Ok(_) => panic!("corrupt data file was parsed"),
+            Err(err) => err,
+        };
+        assert!(matches!(err, AppError::DataCorrupt { .. }));
+        assert_eq!("data file corrupt for 2020-01-01", err.to_string());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, RATE_LIMIT_MAX_CLIENTS);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.check(ip, now));
+        assert!(limiter.check(ip, now));
+        assert!(!limiter.check(ip, now));
+        // Other clients have their own bucket
+        assert!(limiter.check(other, now));
+        // One token is refilled every 30 seconds
+        assert!(limiter.check(ip, now + std::time::Duration::from_secs(30)));
+        assert!(!limiter.check(ip, now + std::time::Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_rate_limiter_max_clients() {
+        let limiter = RateLimiter::new(2, 20);
+        let ip = |n: u8| IpAddr::from([192, 0, 2, n]);
+        let now = Instant::now();
+        let later = |secs| now + Duration::from_secs(secs);
+        let tracked = |n| limiter.buckets.lock().unwrap().contains_key(&ip(n));
+        for n in 0..20 {
+            assert!(limiter.check(ip(n), later(u64::from(n))));
+        }
+        // Nobody is idle, so the least recently seen clients make room
+        assert!(limiter.check(ip(20), later(20)));
+        assert_eq!(19, limiter.buckets.lock().unwrap().len());
+        assert!(!tracked(0) && !tracked(1) && tracked(2));
+        // There's space again, so nothing else is evicted yet
+        assert!(limiter.check(ip(21), later(21)));
+        assert_eq!(20, limiter.buckets.lock().unwrap().len());
+        assert!(tracked(2));
+        // Clients already being tracked don't cause evictions
+        assert!(limiter.check(ip(21), later(21)));
+        assert!(!limiter.check(ip(21), later(21)));
+        assert_eq!(20, limiter.buckets.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        assert_eq!(DEFAULT_RATE_LIMIT, rate_limit(None));
+        assert_eq!(5, rate_limit(Some("5".to_string())));
+        assert_eq!(DEFAULT_RATE_LIMIT, rate_limit(Some("0".to_string())));
+        assert_eq!(DEFAULT_RATE_LIMIT, rate_limit(Some("lots".to_string())));
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let data = index(&[("en.wikipedia.org", 3), ("example.org", 1)]);
+        let split = breakdown(&data);
+        assert_eq!((4, 3, 1), (split.total, split.wikimedia, split.external));
+        assert_eq!(75.0, split.wikimedia_share);
+        assert_eq!(0.0, breakdown(&index(&[])).wikimedia_share);
+    }
+
+    #[test]
+    fn test_snapshot_dates() {
+        let files: Vec<PathBuf> = [
+            "data/shorturls-20200108.gz.data",
+            "data/shorturls-latest.gz.data",
+            "data/shorturls-20200101.gz.data",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        let dates = snapshot_dates(&files);
+        assert_eq!(vec!["2020-01-01", "2020-01-08"], dates.dates);
+        assert_eq!(Some("2020-01-01".to_string()), dates.min);
+        assert_eq!(Some("2020-01-08".to_string()), dates.max);
+        let empty = snapshot_dates(&[]);
+        assert!(empty.dates.is_empty());
+        assert_eq!(None, empty.max);
+    }
+
+    #[test]
+    fn test_domain_names() {
+        let data = || index(&[("b.org", 3), ("c.org", 2), ("a.org", 1)]);
+        assert_eq!(
+            vec!["b.org", "c.org", "a.org"],
+            domain_names(data(), DomainSort::Count)
+        );
+        assert_eq!(
+            vec!["a.org", "b.org", "c.org"],
+            domain_names(data(), DomainSort::Alpha)
+        );
+    }
+
+    #[test]
+    fn test_aggregate_projects() {
+        let data = index(&[
+            ("en.wikipedia.org", 5),
+            ("example.org", 4),
+            ("de.wikipedia.org", 2),
+            ("commons.wikimedia.org", 1),
+        ]);
+        let projects = aggregate_projects(&data);
+        let projects: Vec<(&str, i32, usize)> = projects
+            .iter()
+            .map(|entry| (entry.project.as_str(), entry.count, entry.domains))
+            .collect();
+        assert_eq!(
+            vec![("wikipedia", 7, 2), ("external", 4, 1), ("commons", 1, 1)],
+            projects
+        );
+    }
+
+    #[test]
+    fn test_summarize() {
+        // 20 domains with counts 20, 19, ..., 1
+        let domains: Vec<String> = (1..=20).map(|n| format!("{}.org", n)).collect();
+        let stats: Vec<(&str, i32)> = domains
+            .iter()
+            .enumerate()
+            .map(|(idx, domain)| (domain.as_str(), 20 - idx as i32))
+            .collect();
+        let summary = summarize(&index(&stats));
+        assert_eq!(210, summary.total);
+        assert_eq!(20, summary.distinct_domains);
+        assert_eq!(10.5, summary.mean);
+        assert_eq!(10, summary.p50);
+        assert_eq!(18, summary.p90);
+        assert_eq!(20, summary.p99);
+        // 20 + 19 + ... + 11 = 155
+        assert_eq!(73.81, summary.top10_share);
+
+        let empty = summarize(&index(&[]));
+        assert_eq!(0, empty.p50);
+        assert_eq!(0.0, empty.mean);
+        assert_eq!(0.0, empty.top10_share);
+    }
+
+    #[test]
+    fn test_concentration() {
+        let date = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        // 200 domains with counts 200, 199, ..., 1
+        let domains: Vec<String> = (1..=200).map(|n| format!("{}.org", n)).collect();
+        let stats: Vec<(&str, i32)> = domains
+            .iter()
+            .enumerate()
+            .map(|(idx, domain)| (domain.as_str(), 200 - idx as i32))
+            .collect();
+        let result = concentration(date, &index(&stats));
+        assert_eq!("2020-01-01", result.date);
+        assert_eq!(200, result.distinct_domains);
+        // (n - 1) / 3n for 1..=n
+        assert_eq!(0.3317, result.gini);
+        assert_eq!(2, result.top1pct_domains);
+        // (200 + 199) / 20100
+        assert_eq!(1.99, result.top1pct_share);
+
+        let equal = concentration(date, &index(&[("a.org", 5), ("b.org", 5), ("c.org", 5)]));
+        assert_eq!(0.0, equal.gini);
+        assert_eq!(1, equal.top1pct_domains);
+        let single = concentration(
+            date,
+            &index(&[("a.org", 4), ("b.org", 0), ("c.org", 0), ("d.org", 0)]),
+        );
+        assert_eq!(0.75, single.gini);
+        assert_eq!(100.0, single.top1pct_share);
+        let empty = concentration(date, &index(&[]));
+        assert_eq!(0.0, empty.gini);
+        assert_eq!(0, empty.top1pct_domains);
+        assert_eq!(0.0, empty.top1pct_share);
+    }
+
+    #[test]
+    fn test_sort_stats() {
+        let domains = |stats: &[DomainTemplate]| -> Vec<String> {
+            stats.iter().map(|dinfo| dinfo.domain.clone()).collect()
+        };
+        let mut stats = index(&[("b.org", 3), ("C.org", 2), ("a.org", 1)]).stats;
+        sort_stats(&mut stats, None, None);
+        assert_eq!(vec!["b.org", "C.org", "a.org"], domains(&stats));
+        sort_stats(&mut stats, None, Some(SortOrder::Asc));
+        assert_eq!(vec!["a.org", "C.org", "b.org"], domains(&stats));
+        sort_stats(&mut stats, Some(SortKey::Domain), None);
+        assert_eq!(vec!["a.org", "b.org", "C.org"], domains(&stats));
+        sort_stats(&mut stats, Some(SortKey::Domain), Some(SortOrder::Desc));
+        assert_eq!(vec!["C.org", "b.org", "a.org"], domains(&stats));
+    }
+
+    #[test]
+    fn test_chart_points_avg_per_domain() {
+        let first = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        let second = NaiveDate::from_ymd_opt(2020, 1, 2).unwrap();
+        let mut series = ChartSeries::default();
+        series.push(first, &index(&[("a.org", 4), ("b.org", 2)]), Some("a.org"));
+        series.push(second, &index(&[("a.org", 9)]), Some("a.org"));
+        let opts = ChartOptions {
+            metric: Some(Metric::AvgPerDomain),
+            ..Default::default()
+        };
+        let points = ChartPoints::new(series, &opts);
+        assert_eq!(vec![(first, 3.0), (second, 9.0)], points.datapoints);
+        assert!(points.domainpoints.is_empty());
+        assert_eq!(9.0, points.final_total);
+        assert_eq!("Short URLs per domain", points.metric.label());
+    }
+
+    #[test]
+    fn test_find_domain() {
+        let data = || index(&[("a.org", 5), ("b.org", 3)]);
+        assert_eq!(3, find_domain(data(), "b.org").unwrap().count);
+        assert!(matches!(
+            find_domain(data(), "c.org"),
+            Err(AppError::NotFound(_))
+        ));
+        assert_eq!(
+            "shorturls:domains:shorturls-20200101.gz.data",
+            domain_cache_key(Path::new("./data/shorturls-20200101.gz.data"))
+        );
+    }
+
+    #[test]
+    fn test_data_version() {
+        // Files that can't be read are keyed by name alone
+        assert_eq!(
+            "shorturls-20200101.gz.data",
+            data_version(Path::new("./data/shorturls-20200101.gz.data"))
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shorturls-20200101.gz.data");
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_modified(Utc.with_ymd_and_hms(2020, 1, 2, 0, 0, 0).unwrap().into())
+            .unwrap();
+        assert_eq!(
+            "shorturls-20200101.gz.data@1577923200000",
+            data_version(&path)
+        );
+        assert_eq!(
+            "shorturls:domains:shorturls-20200101.gz.data@1577923200000",
+            domain_cache_key(&path)
+        );
+        // Re-extracting the file with --force moves it to new keys
+        let key = data_cache_key(&path);
+        assert!(key.ends_with("/shorturls-20200101.gz.data@1577923200000"));
+        file.set_modified(Utc.with_ymd_and_hms(2020, 1, 3, 0, 0, 0).unwrap().into())
+            .unwrap();
+        assert_ne!(key, data_cache_key(&path));
+        let today = NaiveDate::from_ymd_opt(2020, 1, 3).unwrap();
+        assert!(
+            chart_cache_key(None, &ChartOptions::default(), &path, today)
+                .ends_with(":shorturls-20200101.gz.data@1578009600000")
+        );
+    }
+
+    #[test]
+    fn test_cached_domain() {
+        let data = index(&[("a.org", 5)]);
+        let json = serde_json::to_string(&data.stats[0]).unwrap();
+        match cached_domain(Some(json), true) {
+            CachedDomain::Found(dinfo) => {
+                assert_eq!(("a.org", 5), (dinfo.domain.as_str(), dinfo.count))
+            }
+            _ => panic!("cached domain wasn't found"),
+        }
+        assert!(matches!(cached_domain(None, true), CachedDomain::Unknown));
+        assert!(matches!(cached_domain(None, false), CachedDomain::Missing));
+        assert!(matches!(
+            cached_domain(Some("{\"domain\": \"a.org\"}".to_string()), true),
+            CachedDomain::Missing
+        ));
+    }
+
+    #[test]
+    fn test_listen_on() {
+        let config = |port: Option<&str>, address: Option<&str>| -> rocket::Config {
+            listen_on(
+                rocket::Config::figment()
+                    .merge(("port", 8000))
+                    .merge(("address", "127.0.0.1")),
+                port.map(str::to_string),
+                address.map(str::to_string),
+            )
+            .extract()
+            .unwrap()
+        };
+        let defaults = config(None, None);
+        assert_eq!(8000, defaults.port);
+        assert_eq!("127.0.0.1".parse::<IpAddr>().unwrap(), defaults.address);
+        let custom = config(Some("8001"), Some("0.0.0.0"));
+        assert_eq!(8001, custom.port);
+        assert_eq!("0.0.0.0".parse::<IpAddr>().unwrap(), custom.address);
+        let invalid = config(Some("http"), Some("localhost"));
+        assert_eq!(8000, invalid.port);
+        assert_eq!("127.0.0.1".parse::<IpAddr>().unwrap(), invalid.address);
+    }
+
+    #[test]
+    fn test_growth_rate() {
+        let first = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        let last = NaiveDate::from_ymd_opt(2020, 1, 4).unwrap();
+        assert_eq!(
+            GrowthRateTemplate {
+                from: "2020-01-01".to_string(),
+                to: "2020-01-04".to_string(),
+                from_total: 10,
+                to_total: 20,
+                growth: 10,
+                days: 3,
+                per_day: Some(3.33),
+            },
+            growth_rate((first, 10), (last, 20))
+        );
+        let same = growth_rate((first, 10), (first, 10));
+        assert_eq!(0, same.days);
+        assert_eq!(None, same.per_day);
+    }
+
+    #[test]
+    fn test_complete() {
+        let domains: Vec<String> = [
+            "en.wikipedia.org",
+            "example.org",
+            "en.wiktionary.org",
+            "de.wikipedia.org",
+        ]
+        .iter()
+        .map(|domain| domain.to_string())
+        .collect();
+        assert_eq!(
+            vec!["en.wikipedia.org", "en.wiktionary.org"],
+            complete(&domains, "en.wik", 10)
+        );
+        assert_eq!(vec!["en.wikipedia.org"], complete(&domains, "en.wik", 1));
+        assert!(complete(&domains, "fr.", 10).is_empty());
+    }
+
+    #[test]
+    fn test_avg_per_domain_points() {
+        let date = |day| NaiveDate::from_ymd_opt(2020, 1, day).unwrap();
+        let series = ChartSeries {
+            total: vec![(date(1), 10), (date(2), 0), (date(3), 30)],
+            domains: vec![(date(1), 4), (date(2), 0), (date(3), 3)],
+            ..Default::default()
+        };
+        let points = avg_per_domain_points(&series);
+        let summary: Vec<(&str, i32, usize, f32)> = points
+            .iter()
+            .map(|point| {
+                (
+                    point.date.as_str(),
+                    point.total,
+                    point.domains,
+                    point.average,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![("2020-01-01", 10, 4, 2.5), ("2020-01-03", 30, 3, 10.0)],
+            summary
+        );
+    }
+
+    #[test]
+    fn test_growth_target() {
+        let to = NaiveDate::from_ymd_opt(2020, 1, 8).unwrap();
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2020, 1, 1).unwrap(),
+            growth_target(to, 7)
+        );
+        // At least a day back
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2020, 1, 7).unwrap(),
+            growth_target(to, 0)
+        );
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2020, 1, 7).unwrap(),
+            growth_target(to, -5)
+        );
+        assert_eq!(NaiveDate::MIN, growth_target(to, 100_000_000));
+        assert_eq!(NaiveDate::MIN, growth_target(to, i64::MAX));
+    }
+
+    #[test]
+    fn test_completion_cache() {
+        let cache = CompletionCache::new();
+        let old = "shorturls-20200101.gz.data@1577836800000";
+        let new = "shorturls-20200101.gz.data@1577923200000";
+        assert!(cache.get(old).is_none());
+        cache.set(old.to_string(), Arc::new(vec!["a.org".to_string()]));
+        assert_eq!(vec!["a.org"], *cache.get(old).unwrap());
+        // A re-extracted data file needs a new list
+        assert!(cache.get(new).is_none());
+        cache.set(new.to_string(), Arc::new(vec![]));
+        assert!(cache.get(old).is_none());
+        assert!(cache.get(new).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_grid_layout() {
+        let opts = ChartOptions::default();
+        assert_eq!(((1, 1), (450, 250)), grid_layout(1, &opts));
+        assert_eq!(((1, 2), (900, 250)), grid_layout(2, &opts));
+        assert_eq!(((2, 2), (900, 500)), grid_layout(3, &opts));
+        assert_eq!(((2, 3), (1350, 500)), grid_layout(5, &opts));
+        assert_eq!(((4, 4), (1800, 1000)), grid_layout(MAX_GRID_PANELS, &opts));
+        let opts = ChartOptions {
+            width: Some(10_000),
+            height: Some(600),
+            ..Default::default()
+        };
+        assert_eq!(((2, 2), (4000, 600)), grid_layout(4, &opts));
+    }
+
+    #[test]
+    fn test_rank_history() {
+        let first = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        let second = NaiveDate::from_ymd_opt(2020, 1, 8).unwrap();
+        assert_eq!(
+            Some(vec![
+                RankHistoryPoint {
+                    date: "2020-01-01".to_string(),
+                    rank: None
+                },
+                RankHistoryPoint {
+                    date: "2020-01-08".to_string(),
+                    rank: Some(3)
+                },
+            ]),
+            rank_history(vec![(first, None), (second, Some(3))])
+        );
+        assert_eq!(None, rank_history(vec![(first, None), (second, None)]));
+        assert_eq!(None, rank_history(vec![]));
+    }
+
+    #[test]
+    fn test_parse_domain_list() {
+        assert_eq!(
+            vec!["a.org", "b.org"],
+            parse_domain_list(Some(" a.org,,b.org, a.org "), 2).unwrap()
+        );
+        for list in [None, Some(""), Some(" , ")] {
+            assert!(matches!(
+                parse_domain_list(list, 2),
+                Err(AppError::BadRequest(_))
+            ));
+        }
+        let err = parse_domain_list(Some("a.org,b.org,c.org"), 2).unwrap_err();
+        assert_eq!(
+            "At most 2 domains can be requested at once",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_chart_cache_key() {
+        let latest = Path::new("./data/shorturls-20200101.gz.data");
+        let today = NaiveDate::from_ymd_opt(2020, 1, 2).unwrap();
+        assert_eq!(
+            chart_cache_key(None, &ChartOptions::default(), latest, today),
+            "shorturls:chart:*:900x300:Linear:Day:Light::::Total::shorturls-20200101.gz.data"
+        );
+        let opts = ChartOptions {
+            width: Some(100),
+            height: Some(500),
+            scale: Some(Scale::Log),
+            interval: Some(Interval::Month),
+            theme: Some(Theme::Dark),
+            color: Some("#FF8800".to_string()),
+            overlay: Some(Overlay::Growth),
+            exclude_today: Some(true),
+            metric: Some(Metric::AvgPerDomain),
+            since: Some("2020-01-15".to_string()),
+        };
+        assert_eq!(
+            chart_cache_key(Some("en.wikipedia.org"), &opts, latest, today),
+            "shorturls:chart:en.wikipedia.org:200x500:Log:Month:Dark:ff8800:Growth:notoday20200102:AvgPerDomain:2020-01-15:shorturls-20200101.gz.data"
+        );
+        // Leaving out today's data file makes the chart change at midnight
+        let tomorrow = today.succ_opt().unwrap();
+        assert_ne!(
+            chart_cache_key(None, &opts, latest, today),
+            chart_cache_key(None, &opts, latest, tomorrow)
+        );
+        let opts = ChartOptions::default();
+        assert_eq!(
+            chart_cache_key(None, &opts, latest, today),
+            chart_cache_key(None, &opts, latest, tomorrow)
+        );
+    }
+
+    #[test]
+    fn test_cutoff_etag() {
+        let cutoff = NaiveDate::from_ymd_opt(2020, 1, 2).unwrap();
+        assert_eq!(
+            cutoff_etag("\"20200101-00000000000000ff\"", cutoff),
+            "\"20200101-00000000000000ff-20200102\""
+        );
+        let fresh = Freshness {
+            last_modified: Some(Utc.with_ymd_and_hms(2020, 1, 1, 5, 0, 0).unwrap()),
+            if_modified_since: None,
+            etag: Some("\"20200101-00000000000000ff\"".to_string()),
+            if_none_match: None,
+        }
+        .with_cutoff(Some(cutoff));
+        assert_eq!(
+            fresh.last_modified,
+            Some(Utc.with_ymd_and_hms(2020, 1, 2, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_chart_options_since() {
+        let opts = ChartOptions {
+            since: Some("2020-01-15".to_string()),
+            ..Default::default()
+        };
+        assert!(opts.validate().is_ok());
+        assert_eq!(NaiveDate::from_ymd_opt(2020, 1, 15), opts.since_date());
+        let opts = ChartOptions {
+            since: Some("20200115".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(Status::BadRequest, opts.validate().unwrap_err().status());
+        assert_eq!(None, opts.since_date());
+        assert_eq!(None, ChartOptions::default().since_date());
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(Some(RGBColor(0xff, 0x88, 0x00)), parse_color("ff8800"));
+        assert_eq!(Some(RGBColor(0x12, 0xab, 0xef)), parse_color("#12ABef"));
+        assert_eq!(None, parse_color("fff"));
+        assert_eq!(None, parse_color("gggggg"));
+        assert_eq!(None, parse_color("ab\u{e9}cd"));
+    }
+
+    #[test]
+    fn test_growth_points() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let points = vec![
+            (date("2020-01-01"), 10.0),
+            (date("2020-01-02"), 15.0),
+            (date("2020-01-03"), 15.0),
+            (date("2020-01-04"), 12.0),
+        ];
+        assert_eq!(
+            vec![
+                (date("2020-01-02"), 5.0),
+                (date("2020-01-03"), 0.0),
+                (date("2020-01-04"), -3.0),
+            ],
+            growth_points(&points)
+        );
+        assert!(growth_points(&points[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_downsample() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let points = vec![
+            (date("2019-12-25"), 1.0),
+            (date("2020-01-01"), 2.0),
+            (date("2020-01-02"), 3.0),
+            (date("2020-01-08"), 4.0),
+            (date("2020-02-05"), 5.0),
+        ];
+        assert_eq!(downsample(points.clone(), Interval::Day), points);
+        assert_eq!(
+            downsample(points.clone(), Interval::Week),
+            vec![
+                (date("2019-12-25"), 1.0),
+                (date("2020-01-02"), 3.0),
+                (date("2020-01-08"), 4.0),
+                (date("2020-02-05"), 5.0),
+            ]
+        );
+        assert_eq!(
+            downsample(points, Interval::Month),
+            vec![
+                (date("2019-12-25"), 1.0),
+                (date("2020-01-08"), 4.0),
+                (date("2020-02-05"), 5.0),
+            ]
+        );
+    }
+
+    /// Property names of a schema in the OpenAPI spec
+    fn schema_properties(spec: &serde_json::Value, name: &str) -> Vec<String> {
+        let mut props: Vec<String> = spec["components"]["schemas"][name]["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        props.sort();
+        props
+    }
+
+    /// Field names of a struct once serialized
+    fn serialized_fields<T: Serialize>(val: &T) -> Vec<String> {
+        let mut fields: Vec<String> = serde_json::to_value(val)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn test_openapi_spec() {
+        let spec = openapi_spec();
+        assert_eq!(
+            schema_properties(&spec, "IndexTemplate"),
+            serialized_fields(&IndexPage::new(index(&[])))
+        );
+        let mut idn = index(&[("xn--d1acpjx3f.xn--p1ai", 1)]);
+        idn.decode_hosts();
+        assert_eq!(
+            schema_properties(&spec, "DomainTemplate"),
+            serialized_fields(&idn.stats[0])
+        );
+        assert_eq!(
+            schema_properties(&spec, "GrowthEntry"),
+            serialized_fields(&growth_entry("example.org", Some(1), Some(2)))
+        );
+    }
+
+    #[test]
+    fn test_is_api_path() {
+        assert!(is_api_path("/api.json"));
+        assert!(is_api_path("/en.wikipedia.org/history.json"));
+        assert!(is_api_path("/autocomplete"));
+        assert!(!is_api_path("/"));
+        assert!(!is_api_path("/en.wikipedia.org"));
+        assert!(!is_api_path("/chart.svg"));
+    }
+
+    #[test]
+    fn test_matcher() {
+        let substring = Matcher::new("Wiki", SearchMode::Substring).unwrap();
+        assert!(substring.is_match("en.wikipedia.org"));
+        assert!(!substring.is_match("example.org"));
+        let prefix = Matcher::new("en.", SearchMode::Prefix).unwrap();
+        assert!(prefix.is_match("en.wikipedia.org"));
+        assert!(!prefix.is_match("de.wikipedia.org"));
+        let regex = Matcher::new(r"^(en|de)\.wiki", SearchMode::Regex).unwrap();
+        assert!(regex.is_match("en.wikipedia.org"));
+        assert!(regex.is_match("DE.wikipedia.org"));
+        assert!(!regex.is_match("fr.wikipedia.org"));
+        assert!(Matcher::new("(", SearchMode::Regex).is_err());
+        assert!(Matcher::new("a{1000}{1000}", SearchMode::Regex).is_err());
+    }
+
+    #[test]
+    fn test_chart_size() {
+        assert_eq!((900, 300), ChartOptions::default().size());
+        let opts = ChartOptions {
+            width: Some(1200),
+            ..Default::default()
+        };
+        assert_eq!((1200, 300), opts.size());
+        let opts = ChartOptions {
+            width: Some(1),
+            height: Some(100_000),
+            ..Default::default()
+        };
+        assert_eq!((200, 4000), opts.size());
+    }
+
+    #[test]
+    fn test_chart_series() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let series = || ChartSeries {
+            total: vec![(date("2020-01-01"), 10), (date("2020-01-02"), 20)],
+            domain: vec![(date("2020-01-01"), 4)],
+            domains: vec![(date("2020-01-01"), 2), (date("2020-01-02"), 4)],
+        };
+        let opts = ChartOptions::default();
+        assert_eq!(
+            json!({ "total": [["2020-01-01", 10], ["2020-01-02", 20]] }),
+            serde_json::to_value(ChartSeriesTemplate::new(series(), &opts, None)).unwrap()
+        );
+        assert_eq!(
+            json!({
+                "total": [["2020-01-01", 10], ["2020-01-02", 20]],
+                "domain": [["2020-01-01", 4]]
+            }),
+            serde_json::to_value(ChartSeriesTemplate::new(
+                series(),
+                &opts,
+                Some("example.org")
+            ))
+            .unwrap()
+        );
+        let opts = ChartOptions {
+            interval: Some(Interval::Month),
+            ..Default::default()
+        };
+        assert_eq!(
+            json!({ "total": [["2020-01-02", 20]] }),
+            serde_json::to_value(ChartSeriesTemplate::new(series(), &opts, None)).unwrap()
+        );
+        let opts = ChartOptions {
+            metric: Some(Metric::AvgPerDomain),
+            ..Default::default()
+        };
+        assert_eq!(
+            json!({ "total": [["2020-01-01", 5], ["2020-01-02", 5]] }),
+            serde_json::to_value(ChartSeriesTemplate::new(series(), &opts, None)).unwrap()
+        );
+        // Counts too big for an f32 to hold exactly are kept as they are
+        let big = ChartSeries {
+            total: vec![(date("2020-01-01"), 16_777_217)],
+            ..Default::default()
+        };
+        assert_eq!(
+            json!({ "total": [["2020-01-01", 16_777_217]] }),
+            serde_json::to_value(ChartSeriesTemplate::new(
+                big,
+                &ChartOptions::default(),
+                None
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_draw_chart_empty() {
+        use plotters::prelude::*;
+        let points = ChartPoints {
+            dates: vec![],
+            datapoints: vec![],
+            domainpoints: vec![],
+            growthpoints: vec![],
+            final_total: 0.0,
+            metric: Metric::Total,
+        };
+        let mut buf = String::new();
+        {
+            let root_area = SVGBackend::with_string(&mut buf, CHART_SIZE).into_drawing_area();
+            draw_chart(&root_area, points, "Empty", &ChartOptions::default()).unwrap();
+        }
+        assert!(buf.contains(NO_DATA));
     }
 }